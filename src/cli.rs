@@ -1,59 +1,346 @@
+use crate::config::load_config;
+use crate::exclude::ExcludeRules;
+use crate::export::read_scan_file;
+use crate::logger::parse_log_level;
+use crate::scan_cache::{cache_file_path, ScanCache};
 use crate::types::*;
 use home::home_dir;
-use std::env::{args, current_dir};
-use std::fs::canonicalize;
-use std::path::PathBuf;
+use log::LevelFilter;
+use std::env::{args_os, current_dir};
+use std::fs::{canonicalize, symlink_metadata};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::MetadataExt;
 
 pub fn parse_args() -> Args {
-    let mut args = args();
+    let mut args = args_os();
     args.next();
-    let args_list: Vec<String> = args.collect();
 
-    if args_list.len() == 1 {
-        // dirp <file-path>
-        return Args {
-            path: normalize_file_path(&args_list[0]),
-        };
+    let mut path = None;
+    let mut size_mode = SizeMode::Apparent;
+    let mut remove_mode = RemoveMode::Trash;
+    let mut is_dry_run = false;
+    let mut plan_path = None;
+    let mut is_confirmed = false;
+    let mut is_apply = false;
+    let mut apply_plan_path = None;
+    let mut is_undo = false;
+    let mut one_file_system = false;
+    let mut is_report = false;
+    let mut report_depth = 1;
+    let mut export = None;
+    let mut scan_file_path = None;
+    let mut use_cache = true;
+    let mut log_path = None;
+    let mut log_level = LevelFilter::Info;
+
+    // Exclude settings start from the config file, and the command line adds to them.
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{}", error);
+            exit(-1);
+        }
+    };
+    let mut exclude_list = config.exclude_list;
+    let mut use_ignore_files = config.use_ignore_files;
+    let mut show_excluded = config.show_excluded;
+
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--apparent-size") => size_mode = SizeMode::Apparent,
+            Some("--disk-usage") => size_mode = SizeMode::Disk,
+            Some("-x" | "--one-file-system") => one_file_system = true,
+            Some("--permanent") => remove_mode = RemoveMode::Permanent,
+            Some("--dry-run") => is_dry_run = true,
+            Some("-y" | "--yes") => is_confirmed = true,
+            Some("--plan") => match args.next() {
+                Some(file_path) => {
+                    is_dry_run = true;
+                    plan_path = Some(PathBuf::from(file_path));
+                }
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("--exclude") => match args.next().and_then(|glob| glob.into_string().ok()) {
+                Some(glob) => exclude_list.push(glob),
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("--gitignore") => use_ignore_files = true,
+            Some("--show-excluded") => show_excluded = true,
+            Some("--no-cache") => use_cache = false,
+            Some("--report") => is_report = true,
+            Some(flag @ ("--json" | "--ncdu")) => match args.next() {
+                Some(file_path) => {
+                    let export_format = match flag {
+                        "--ncdu" => ExportFormat::Ncdu,
+                        _ => ExportFormat::Json,
+                    };
+                    export = Some((PathBuf::from(file_path), export_format));
+                }
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("-f" | "--load") => match args.next() {
+                Some(file_path) => scan_file_path = Some(PathBuf::from(file_path)),
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("--log") => match args.next() {
+                Some(file_path) => log_path = Some(PathBuf::from(file_path)),
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("--log-level") => match args
+                .next()
+                .and_then(|level| parse_log_level(level.to_str()?))
+            {
+                Some(level) => log_level = level,
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            Some("--depth") => match args
+                .next()
+                .and_then(|depth| depth.to_str()?.parse::<u32>().ok())
+            {
+                Some(depth) => report_depth = depth,
+                None => {
+                    print_usage();
+                    exit(-1);
+                }
+            },
+            _ => {
+                if arg.as_encoded_bytes().starts_with(b"-") {
+                    print_usage();
+                    exit(-1);
+                } else if arg == "apply" && !is_apply && !is_undo && path.is_none() {
+                    // dirp apply <plan-path>
+                    is_apply = true;
+                } else if is_apply && apply_plan_path.is_none() {
+                    apply_plan_path = Some(PathBuf::from(arg));
+                } else if arg == "undo" && !is_apply && !is_undo && path.is_none() {
+                    // dirp undo
+                    is_undo = true;
+                } else if is_apply || is_undo || path.is_some() {
+                    print_usage();
+                    exit(-1);
+                } else {
+                    // dirp <file-path>
+                    match normalize_file_path(Path::new(&arg)) {
+                        Ok(file_path) => path = Some(file_path),
+                        Err(error) => {
+                            println!("Can not read '{}': {}", Path::new(&arg).display(), error);
+                            print_usage();
+                            exit(-1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if is_apply || is_undo {
+        // The plan, or the journal, says where to look.
+        if (is_apply && apply_plan_path.is_none()) || scan_file_path.is_some() {
+            print_usage();
+            exit(-1);
+        }
+        path = Some(current_dir().expect("Can not read the current working directory."));
+        use_cache = false;
+    }
+
+    let (path, scan_source) = match (path, scan_file_path) {
+        (Some(path), None) => (path, ScanSource::Disk),
+        (None, Some(scan_file_path)) => match read_scan_file(&scan_file_path) {
+            Ok(root_dir) => (
+                PathBuf::from(&root_dir.path),
+                ScanSource::Snapshot(root_dir),
+            ),
+            Err(error) => {
+                println!(
+                    "Can not load '{}': {}",
+                    scan_file_path.to_string_lossy(),
+                    error
+                );
+                exit(-1);
+            }
+        },
+        _ => {
+            print_usage();
+            exit(-1);
+        }
+    };
+
+    // For 'apply', only whether it's set matters. Each path in the plan stays on the
+    // file system it's on.
+    let root_dev = if one_file_system && matches!(scan_source, ScanSource::Disk) {
+        let meta_data = symlink_metadata(&path).expect("Can not read the directory path.");
+        Some(meta_data.st_dev())
     } else {
-        print_usage();
-        exit(-1);
+        None
+    };
+    let exclude_rules = match ExcludeRules::new(&exclude_list, use_ignore_files) {
+        Ok(exclude_rules) => exclude_rules,
+        Err(error) => {
+            println!("Bad exclude pattern: {}", error);
+            exit(-1);
+        }
+    };
+    // Anything that changes what a dir's entries are needs to be part of the key.
+    let scan_cache = match (use_cache, &scan_source) {
+        (true, ScanSource::Disk) => cache_file_path(&path).map(|cache_file_path| {
+            let options_key = format!(
+                "one-file-system={} exclude={:?} gitignore={} show-excluded={}",
+                one_file_system, exclude_list, use_ignore_files, show_excluded
+            );
+            Arc::new(ScanCache::load(cache_file_path, options_key))
+        }),
+        _ => None,
+    };
+    let scan_options = ScanOptions {
+        root_dev,
+        exclude_rules,
+        show_excluded,
+        scan_cache,
+    };
+
+    let command = match (is_report, export, apply_plan_path, is_undo) {
+        (true, None, None, false) => Command::Report {
+            depth: report_depth,
+        },
+        (false, Some((export_path, export_format)), None, false) => Command::Export {
+            export_path,
+            export_format,
+        },
+        (false, None, Some(plan_path), false) => Command::Apply { plan_path },
+        (false, None, None, true) => Command::Undo,
+        (false, None, None, false) => Command::Ui,
+        _ => {
+            print_usage();
+            exit(-1);
+        }
+    };
+
+    Args {
+        command,
+        path,
+        size_mode,
+        remove_mode,
+        is_dry_run,
+        plan_path,
+        is_confirmed,
+        scan_source,
+        scan_options,
+        log_path,
+        log_level,
     }
 }
 
 //
-// A normalized file path is one that has the '~' (home dir) character
-// removed, as well as the '.' (current dir) character removed, and is then
-// 'canonicalize' according to the fs library.
+// A normalized file path is one that has a leading '~' (home dir) component replaced
+// with the home dir, and is then 'canonicalize' according to the fs library, which also
+// resolves '.' and '..' against the current dir. A path that can't be resolved (an empty
+// one included) is an error, for the caller to report.
 //
-fn normalize_file_path(file_path: &String) -> PathBuf {
-    let mut file_path = file_path.clone();
-    if file_path.chars().collect::<Vec<char>>()[0] == '~' {
-        file_path = home_dir()
-            .expect("~ can not be resolved to a home directory.")
-            .to_string_lossy()
-            .to_string();
-    }
-    if file_path.chars().collect::<Vec<char>>()[0] == '.' {
-        file_path = current_dir()
-            .expect(". can not be resolved to the current working directory.")
-            .to_string_lossy()
-            .to_string();
-    }
+fn normalize_file_path(file_path: &Path) -> io::Result<PathBuf> {
+    let file_path = match file_path.strip_prefix("~") {
+        Ok(rest_path) => match home_dir() {
+            Some(home_dir_path) => home_dir_path.join(rest_path),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "~ can not be resolved to a home directory",
+                ))
+            }
+        },
+        Err(_) => file_path.to_path_buf(),
+    };
 
-    canonicalize(PathBuf::from(file_path)).expect("")
+    canonicalize(file_path)
 }
 
 fn print_usage() {
     println!("");
     println!("A directory profiler.");
     println!("");
-    println!("USAGE: dirp [directory path]");
+    println!("USAGE: dirp [options] [directory path]");
+    println!("       dirp [options] -f <saved scan file>");
+    println!("       dirp [options] apply <removal plan file>");
+    println!("       dirp [options] undo");
     println!("");
+    println!("Options:");
+    println!();
+    println!("    --apparent-size      - Size files by their length (the default).");
+    println!("    --disk-usage         - Size files by the disk blocks allocated to them.");
+    println!("    -x, --one-file-system");
+    println!("                         - Don't cross into other file systems. Mount points");
+    println!("                           are listed, but not scanned.");
+    println!("    --permanent          - Have 'x' delete marked paths for good, rather than");
+    println!("                           move them to the Trash.");
+    println!("    --dry-run            - Have 'x' print a removal plan (each marked path, its");
+    println!("                           size and mtime, and the total freed) instead of");
+    println!("                           removing anything.");
+    println!("    --plan <file>        - As --dry-run, but write the plan to <file>.");
+    println!("    -y, --yes            - Don't ask before 'apply' removes, or 'undo' restores.");
+    println!("    --exclude <glob>     - Leave out paths matching <glob>. May be repeated.");
+    println!("    --gitignore          - Also leave out what .gitignore and .ignore files do.");
+    println!("    --show-excluded      - List excluded paths greyed out, instead of hiding");
+    println!("                           them. Their sizes aren't added to their parents.");
+    println!("    --no-cache           - Read every directory, rather than reusing what was");
+    println!("                           found in unchanged ones last time.");
+    println!("    --report             - Print the sized tree once the scan is done, and exit,");
+    println!("                           instead of opening the UI.");
+    println!("    --depth <n>          - How many levels below the directory --report prints.");
+    println!("                           Defaults to 1.");
+    println!("    --json <file>        - Write the scanned tree to <file> as JSON once the scan");
+    println!("                           is done, and exit, instead of opening the UI.");
+    println!("    --ncdu <file>        - As --json, but in ncdu's format (as 'ncdu -o' writes).");
+    println!("    -f, --load <file>    - Browse a scan saved with --json, --ncdu (or 'ncdu -o'),");
+    println!("                           or 'e', instead of scanning. Nothing is removed from");
+    println!("                           a saved scan.");
+    println!("    --log <file>         - Append a log of what dirp does (scans, errors, what's");
+    println!("                           removed) to <file>.");
+    println!("    --log-level <level>  - How much goes in the log: error, warn, info, debug or");
+    println!("                           trace. Defaults to info.");
+    println!();
+    println!("Excludes can also be set in ~/.config/dirp/config, and paths listed in a");
+    println!(".dirpignore file are always left out.");
+    println!();
+    println!("'apply' removes the paths listed in a removal plan (see --plan, and 's'), but");
+    println!("only those whose size and mtime still match the plan. It honors --permanent,");
+    println!("--dry-run and the exclude options.");
+    println!();
+    println!("'undo' puts back what dirp last moved to the Trash (Linux only). It honors");
+    println!("--dry-run and --yes.");
+    println!();
     println!("Key Bindings:");
     println!("");
     println!("    Up Arrow, p          - Move selection up.");
     println!("    Down Arrow, n        - Move selection down.");
+    println!("    /                    - Search: jump to rows whose names (fuzzy) match what");
+    println!("                           you type. Enter to stop typing, Esc to cancel.");
+    println!("    Tab, Shift-Tab       - Jump to the next/previous match.");
+    println!("    F                    - Filter: show only what matches what you type, and the");
+    println!("                           directories that lead to it. Esc clears it.");
     println!("    ");
     println!("    Left Arrow           - Show directory contents.");
     println!("    Right Arrow          - Hide directory contents.");
@@ -62,7 +349,51 @@ fn print_usage() {
     println!("    d                    - Mark/unmark selection for removal.");
     println!("    Delete, Backspace    - Toggle selection for removal.");
     println!("    ");
+    println!("    a                    - Toggle between apparent size and disk usage.");
+    println!("    e                    - Export the scanned tree to a JSON file in the current");
+    println!("                           directory.");
+    println!("    r                    - Rescan the selected directory (or the directory holding");
+    println!("                           the selected file).");
+    println!("    E                    - Show/hide the paths that couldn't be read.");
+    println!("    ");
+    println!("    s                    - Save the marked set as a removal plan, in the current");
+    println!("                           directory, for 'dirp apply'.");
+    println!("    P                    - Toggle between moving marked files to the Trash, and");
+    println!("                           deleting them permanently.");
     println!("    x                    - Remove marked files, and exit program.");
-    println!("    q                    - Exit program.");
+    println!("    q, Ctrl-C            - Exit program.");
     println!("    ");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_file_path() {
+        assert_eq!(
+            normalize_file_path(Path::new(".")).ok(),
+            current_dir().ok(),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            normalize_file_path(Path::new("./test/a")).ok(),
+            canonicalize("test/a").ok(),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            normalize_file_path(Path::new("~")).ok(),
+            home_dir().and_then(|home_dir_path| canonicalize(home_dir_path).ok()),
+            "Error: Unexpected result."
+        );
+        // Errors, rather than panics.
+        assert!(
+            normalize_file_path(Path::new("")).is_err(),
+            "Error: Unexpected result."
+        );
+        assert!(
+            normalize_file_path(Path::new("./test/no_such_path")).is_err(),
+            "Error: Unexpected result."
+        );
+    }
+}
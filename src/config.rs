@@ -0,0 +1,82 @@
+use home::home_dir;
+use std::{env, fs, path::PathBuf};
+
+//
+// Per user settings, read from $XDG_CONFIG_HOME/dirp/config (~/.config/dirp/config if
+// XDG_CONFIG_HOME isn't set). The file is optional. Each line is a 'key = value' pair,
+// and lines starting with '#' are comments. For example:
+//
+//     # Never show these.
+//     exclude = node_modules
+//     exclude = *.o
+//     gitignore = true
+//     show-excluded = false
+//
+// Command line options are applied on top of these.
+//
+#[derive(Debug, Default)]
+pub struct Config {
+    pub exclude_list: Vec<String>,
+    pub use_ignore_files: bool,
+    pub show_excluded: bool,
+}
+
+pub fn config_file_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => {
+            Some(PathBuf::from(config_home).join("dirp").join("config"))
+        }
+        _ => Some(home_dir()?.join(".config").join("dirp").join("config")),
+    }
+}
+
+pub fn load_config() -> Result<Config, String> {
+    let mut config = Config::default();
+
+    let config_file_path = match config_file_path() {
+        Some(config_file_path) => config_file_path,
+        None => return Ok(config),
+    };
+    let contents = match fs::read_to_string(&config_file_path) {
+        Ok(contents) => contents,
+        Err(_error) => {
+            // No config file.
+            return Ok(config);
+        }
+    };
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = || {
+            format!(
+                "{}:{}: can't make sense of '{}'.",
+                config_file_path.to_string_lossy(),
+                index + 1,
+                line
+            )
+        };
+        let (key, value) = line.split_once('=').ok_or_else(error)?;
+        let value = value.trim().to_string();
+
+        match key.trim() {
+            "exclude" => config.exclude_list.push(value),
+            "gitignore" => config.use_ignore_files = parse_bool(&value).ok_or_else(error)?,
+            "show-excluded" => config.show_excluded = parse_bool(&value).ok_or_else(error)?,
+            _ => return Err(error()),
+        }
+    }
+
+    Ok(config)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
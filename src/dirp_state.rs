@@ -1,78 +1,357 @@
+use crate::fs_watcher::FsWatcher;
+use crate::journal::record_session;
+use crate::plan::{write_plan, PlanEntry};
 use crate::types::*;
 use crate::utils::*;
-use chrono::Duration;
 use console::Term;
 use dialoguer::{console, theme::ColorfulTheme, FuzzySelect};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use log::{debug, error, info, trace, warn};
 use std::{
-    sync::mpsc::{Receiver, Sender},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 use threadpool::ThreadPool;
-use timer::MessageTimer;
 use trash;
 
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::MetadataExt;
+
+// How long changes are gathered, from the first, before they are sent on together.
+const FLUSH_DELAY: Duration = Duration::from_millis(50);
+
 pub fn dirp_state_thread_spawn(
-    path: String,
+    path: PathBuf,
+    size_mode: SizeMode,
+    scan_source: ScanSource,
+    scan_options: Arc<ScanOptions>,
     user_sender: Sender<UserMessage>,
     dirp_state_sender: Sender<DirpStateMessage>,
     dirp_state_receiver: Receiver<DirpStateMessage>,
 ) -> JoinHandle<()> {
-    thread::spawn(move || {
-        if let Err(error) =
-            dirp_state_loop(path, user_sender, dirp_state_sender, dirp_state_receiver)
-        {
-            panic!("dirp_state_loop error: {:#?}", error);
-        }
-    })
+    thread::Builder::new()
+        .name("dirp-state".to_string())
+        .spawn(move || {
+            if let Err(error) = dirp_state_loop(
+                path,
+                size_mode,
+                scan_source,
+                scan_options,
+                user_sender,
+                dirp_state_sender,
+                dirp_state_receiver,
+            ) {
+                error!("dirp_state_loop error: {:?}", error);
+                panic!("dirp_state_loop error: {:#?}", error);
+            }
+        })
+        .expect("Can not start the state thread.")
 }
 
 //
 // This thread manages the enter state of this program. It has an object on the stack
-// called 'dirp_state'. That object is a hash from String (a file path) to a Dir object
+// called 'dirp_state'. That object is a hash from PathBuf (a file path) to a Dir object
 // (Directory meta data gleaned from the file system).
 //
 // You interact with this thread (and it's state) by sending it messages on a channel.
 //
-// This thread runs a timer. Every time the timer kicks off a 'dirty state' vairable is checked ('is_state_dirty').
-// If that var is true then the thread cooks up a representation of what state is currently being displayed to the
-// user (a proper subset of the sate in 'dirp_state') and sends it along.
+// Any message that changes the state sets 'is_state_dirty', and starts the clock on a
+// flush ('flush_deadline'). Whatever else changes before FLUSH_DELAY is up goes out with
+// it, so a busy scan sends a few updates a second rather than one per dir, and nothing is
+// sent while nothing changes. An update (StateUpdate) carries only what has changed in
+// the dirs since the last one, which 'dirp_state' keeps track of. The UI keeps its own
+// copy. A filter ('filter') is kept up to date from those same changes.
+//
+// A file system watcher ('fs_watcher'), watching each dir as it's taken in, keeps
+// 'dirp_state' current while the program is open. Changes it reports are collected in
+// 'pending_rescan_set' (a burst of writes into one dir becomes a single entry), and on
+// the next flush each of those dirs is rescanned on its own.
+//
+// Every scan handed to the thread pool is counted in 'scan_progress.pending_scan_count',
+// and counted off when its result comes back. When it reaches zero every size in
+// 'dirp_state' is final. A GetFullState request that arrives before then is held
+// ('is_full_state_requested') and answered at that point. 'scan_progress' goes out with
+// every state response, so the UI can show how the scan is going.
+//
+// Scanned dirs carry what couldn't be read in them, and updates pass that on with the
+// dirs. The dirs that have any are kept in 'error_dir_set', so a full state response can
+// list every error without walking the whole of 'dirp_state'.
 //
 pub fn dirp_state_loop(
-    root_path: String,
+    root_path: PathBuf,
+    mut size_mode: SizeMode,
+    scan_source: ScanSource,
+    scan_options: Arc<ScanOptions>,
     user_sender: Sender<UserMessage>,
     dirp_state_sender: Sender<DirpStateMessage>,
     dirp_state_receiver: Receiver<DirpStateMessage>,
 ) -> Result<(), DirpError> {
     let mut dirp_state = DirHash::new();
-    let threadpool = ThreadPool::new(30);
+    let mut inode_hash = InodeHash::new();
+    let threadpool = ThreadPool::with_name("dirp-scan".to_string(), 30);
     let mut is_state_dirty = false;
+    let mut is_marked_summary_dirty = false;
+    let mut marked_summary = MarkedSummary::default();
+    let mut pending_rescan_set = HashSet::<PathBuf>::new();
+    let mut scan_progress = ScanProgress::default();
+    let scan_start_time = Instant::now();
+    let mut is_full_state_requested = false;
+    let mut kept_state_hash = HashMap::<PathBuf, KeptState>::new();
+    let mut error_dir_set = HashSet::<PathBuf>::new();
+    // Scans that came in before their parent's, by parent path.
+    let mut held_dir_hash = HashMap::<PathBuf, Vec<Dir>>::new();
+    let mut filter: Option<Filter> = None;
+    let mut is_filter_new = false;
+    let matcher = SkimMatcherV2::default();
 
-    // Initialize dir scan.
-    scan_dir_path_in_threadpool(
-        root_path.clone(),
-        true,
-        dirp_state_sender.clone(),
-        &threadpool,
-    );
+    let is_snapshot = matches!(scan_source, ScanSource::Snapshot(_));
+    let mut fs_watcher = match scan_source {
+        ScanSource::Disk => {
+            // Initialize dir scan.
+            scan_progress.pending_scan_count += 1;
+            scan_dir_path_in_threadpool(
+                root_path.clone(),
+                true,
+                scan_options.clone(),
+                dirp_state_sender.clone(),
+                &threadpool,
+            );
+
+            // Watch for changes, in each dir as it's taken in.
+            match FsWatcher::new(dirp_state_sender.clone()) {
+                Ok(fs_watcher) => Some(fs_watcher),
+                Err(error) => {
+                    // Changes on disk just won't be picked up. 'r' still works.
+                    warn!("Can not watch {}: {:?}", root_path.display(), error);
+                    None
+                }
+            }
+        }
+        ScanSource::Snapshot(root_dir) => {
+            // Nothing to scan or watch. The saved scan is all there is.
+            load_snapshot_dir(root_dir, &mut dirp_state, &mut scan_progress);
+            for dir in dirp_state.values() {
+                if !dir.error_list.is_empty() {
+                    error_dir_set.insert(dir.path.clone());
+                }
+            }
+            scan_progress.is_complete = true;
+            is_state_dirty = true;
+            None
+        }
+    };
 
-    // Kick off timer.
-    let message_timer = MessageTimer::new(dirp_state_sender.clone());
-    let _message_timer_guard =
-        message_timer.schedule_repeating(Duration::milliseconds(200), DirpStateMessage::Timer);
+    let mut flush_deadline: Option<Instant> = None;
 
     // Event Loop
     loop {
-        match dirp_state_receiver.recv() {
-            Ok(message) => match message {
+        if flush_deadline.is_none() && (is_state_dirty || !pending_rescan_set.is_empty()) {
+            flush_deadline = Some(Instant::now() + FLUSH_DELAY);
+        }
+        // Checked before taking another message, so a busy queue can't put a flush off.
+        let message = match flush_deadline {
+            Some(deadline) if deadline <= Instant::now() => Ok(DirpStateMessage::Flush),
+            Some(deadline) => match dirp_state_receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Err(RecvTimeoutError::Timeout) => Ok(DirpStateMessage::Flush),
+                result => result.map_err(|_error| ()),
+            },
+            None => dirp_state_receiver.recv().map_err(|_error| ()),
+        };
+        match message {
+            Ok(message) => match log_message(message) {
                 DirpStateMessage::DirScanMessage(dir) => {
-                    process_dir_scan_message(dir, &mut dirp_state, &dirp_state_sender, &threadpool);
-                    is_state_dirty = true;
+                    scan_progress.pending_scan_count -= 1;
+
+                    // Scans come back in whatever order the thread pool finishes them. A
+                    // dir is only taken in once its parent has been, so every dir in
+                    // 'dirp_state' has its ancestors there too, and sizes add up the same
+                    // whichever order that is.
+                    let mut dir_list = vec![dir];
+                    while let Some(mut dir) = dir_list.pop() {
+                        let is_new_dir = !dirp_state.contains_key(&dir.path);
+                        if dir.path == root_path {
+                            // The root is shown whatever the exclude rules say.
+                            dir.is_excluded = false;
+                        }
+                        if !is_new_dir {
+                            // A rescan of a dir the watcher saw change. Only its own
+                            // listing is new, so its sub dirs are kept as they are, and
+                            // taken back in with it.
+                            if let Some(old_dir) = remove_for_rescan(
+                                &dir.path,
+                                false,
+                                &mut dirp_state,
+                                &mut inode_hash,
+                                &mut kept_state_hash,
+                            ) {
+                                remove_gone_sub_dirs(
+                                    &old_dir,
+                                    &dir,
+                                    &mut dirp_state,
+                                    &mut inode_hash,
+                                );
+                            }
+                        } else if dir.path != root_path {
+                            match parent_file_path(&dir.path) {
+                                Some(parent_path) if !dirp_state.contains_key(&parent_path) => {
+                                    // The parent was dropped for a rescan that hasn't come
+                                    // back yet. Held until it does.
+                                    held_dir_hash.entry(parent_path).or_default().push(dir);
+                                    continue;
+                                }
+                                _ if !is_dir_listed_in_parent(&dir.path, &dirp_state) => {
+                                    // Removed while this scan was in flight.
+                                    continue;
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        let dir_path = dir.path.clone();
+                        if is_new_dir {
+                            scan_progress.dirs_scanned += 1;
+                            for fs_obj in &dir.dir_obj_list {
+                                if let FSObj::File(_) = fs_obj {
+                                    scan_progress.files_scanned += 1;
+                                }
+                            }
+                        }
+                        inherit_mark(&mut dir, &dirp_state);
+                        if !kept_state_hash.is_empty() {
+                            restore_kept_state(&mut dir, &mut kept_state_hash);
+                        }
+                        if !dir.error_list.is_empty() {
+                            error_dir_set.insert(dir_path.clone());
+                        }
+                        if let Some(fs_watcher) = &mut fs_watcher {
+                            // A rescanned dir is still watched from when it was new.
+                            if is_new_dir && !dir.is_excluded {
+                                fs_watcher.watch_dir(&dir_path);
+                            }
+                        }
+                        process_dir_scan_message(
+                            dir,
+                            &mut dirp_state,
+                            &mut inode_hash,
+                            &mut scan_progress.pending_scan_count,
+                            &scan_options,
+                            &dirp_state_sender,
+                            &threadpool,
+                        );
+                        is_state_dirty = true;
+                        // Only worth recomputing if there is something marked for this to change.
+                        is_marked_summary_dirty |= marked_summary.size_in_bytes > 0;
+
+                        // Whatever was waiting on this dir can go in now.
+                        if let Some(held_dir_list) = held_dir_hash.remove(&dir_path) {
+                            dir_list.extend(held_dir_list);
+                        }
+                    }
+
+                    if scan_progress.pending_scan_count == 0 {
+                        // Anything not restored or taken in by now no longer exists.
+                        kept_state_hash.clear();
+                        held_dir_hash.clear();
+                    }
+                    if scan_progress.pending_scan_count == 0 && !scan_progress.is_complete {
+                        // The first full scan is done. Later rescans don't change that.
+                        scan_progress.is_complete = true;
+                        scan_progress.elapsed = scan_start_time.elapsed();
+                    }
+                    if scan_progress.pending_scan_count == 0 && is_full_state_requested {
+                        is_full_state_requested = false;
+                        user_sender.send(UserMessage::GetFullStateResponse(
+                            build_full_state_response(
+                                &root_path,
+                                size_mode,
+                                scan_progress,
+                                build_scan_error_list(&mut error_dir_set, &dirp_state),
+                                &dirp_state,
+                            ),
+                        ))?;
+                    }
+                }
+                DirpStateMessage::GetFullState => {
+                    if scan_progress.pending_scan_count == 0 {
+                        user_sender.send(UserMessage::GetFullStateResponse(
+                            build_full_state_response(
+                                &root_path,
+                                size_mode,
+                                scan_progress,
+                                build_scan_error_list(&mut error_dir_set, &dirp_state),
+                                &dirp_state,
+                            ),
+                        ))?;
+                    } else {
+                        is_full_state_requested = true;
+                    }
                 }
-                DirpStateMessage::Timer => {
+                DirpStateMessage::Flush => {
+                    flush_deadline = None;
+                    for path in pending_rescan_set.drain() {
+                        if let Some(dir) = dirp_state.get(&path) {
+                            scan_progress.pending_scan_count += 1;
+                            scan_dir_path_in_threadpool(
+                                path,
+                                dir.is_open,
+                                scan_options.clone(),
+                                dirp_state_sender.clone(),
+                                &threadpool,
+                            );
+                        }
+                    }
                     if is_state_dirty {
                         is_state_dirty = false;
-                        user_sender.send(UserMessage::GetStateResponse(GetStateResponse {
-                            dirp_state: build_result_tree(&root_path, false, &dirp_state),
+                        if is_marked_summary_dirty {
+                            is_marked_summary_dirty = false;
+                            marked_summary =
+                                build_marked_summary(&root_path, size_mode, &dirp_state);
+                        }
+                        if !scan_progress.is_complete {
+                            scan_progress.elapsed = scan_start_time.elapsed();
+                        }
+                        scan_progress.size_in_bytes = dirp_state
+                            .get(&root_path)
+                            .map_or(0, |root_dir| root_dir.size_in_bytes_for(size_mode));
+                        let dir_change_list = dirp_state.take_dir_change_list();
+                        if let Some(filter) = &mut filter {
+                            for dir_change in &dir_change_list {
+                                match dir_change {
+                                    DirChange::Listed(dir) => {
+                                        filter.list_dir(&root_path, dir, &matcher)
+                                    }
+                                    DirChange::Removed(path) => filter.remove_dir(&root_path, path),
+                                    DirChange::Updated(_) => {}
+                                }
+                            }
+                        }
+                        let filter_change = match (is_filter_new, &mut filter) {
+                            (true, Some(filter)) => {
+                                filter.changed_path_set.clear();
+                                Some(FilterChange::On(filter.shown_path_list()))
+                            }
+                            (true, None) => Some(FilterChange::Off),
+                            (false, Some(filter)) => filter.take_filter_change(),
+                            (false, None) => None,
+                        };
+                        is_filter_new = false;
+                        user_sender.send(UserMessage::StateUpdate(StateUpdate {
+                            dir_change_list,
+                            filter_change,
+                            size_mode,
+                            marked_summary,
+                            scan_progress,
                         }))?;
                     }
                 }
@@ -95,23 +374,109 @@ pub fn dirp_state_loop(
                     }
                 }
                 DirpStateMessage::MarkPath(path) => {
-                    do_mark_deep(&path, true, &mut dirp_state);
+                    do_mark_deep(&path, true, &mut dirp_state, &mut kept_state_hash);
                     is_state_dirty = true;
+                    is_marked_summary_dirty = true;
                 }
                 DirpStateMessage::UnmarkPath(path) => {
-                    do_mark_deep(&path, false, &mut dirp_state);
+                    do_mark_deep(&path, false, &mut dirp_state, &mut kept_state_hash);
                     is_state_dirty = true;
+                    is_marked_summary_dirty = true;
                 }
                 DirpStateMessage::ToggleMarkPath(path) => {
                     if let Some(is_path_marked) = is_path_marked(&path, &dirp_state) {
-                        do_mark_deep(&path, !is_path_marked, &mut dirp_state);
+                        do_mark_deep(
+                            &path,
+                            !is_path_marked,
+                            &mut dirp_state,
+                            &mut kept_state_hash,
+                        );
                         is_state_dirty = true;
+                        is_marked_summary_dirty = true;
+                    } else {
+                        // e.g. removed on disk since the UI last showed it.
+                        warn!(
+                            "Can not toggle the mark on {}: it's not in the tree.",
+                            path.display()
+                        );
+                    }
+                }
+                DirpStateMessage::ToggleSizeMode => {
+                    size_mode = size_mode.toggled();
+                    is_state_dirty = true;
+                    is_marked_summary_dirty = true;
+                }
+                DirpStateMessage::SetFilter(query) => {
+                    filter =
+                        query.map(|query| Filter::new(query, &root_path, &matcher, &dirp_state));
+                    is_filter_new = true;
+                    is_state_dirty = true;
+                }
+                DirpStateMessage::Rescan(path) => {
+                    if !is_snapshot {
+                        // A file is rescanned along with the dir that holds it.
+                        let dir_path = match dirp_state.contains_key(&path) {
+                            true => Some(path),
+                            false => parent_file_path(&path),
+                        };
+                        if let Some(old_dir) = dir_path.and_then(|dir_path| {
+                            remove_for_rescan(
+                                &dir_path,
+                                true,
+                                &mut dirp_state,
+                                &mut inode_hash,
+                                &mut kept_state_hash,
+                            )
+                        }) {
+                            scan_progress.pending_scan_count += 1;
+                            scan_dir_path_in_threadpool(
+                                old_dir.path,
+                                old_dir.is_open,
+                                scan_options.clone(),
+                                dirp_state_sender.clone(),
+                                &threadpool,
+                            );
+                            is_state_dirty = true;
+                            is_marked_summary_dirty = true;
+                        }
+                    }
+                }
+                DirpStateMessage::PathCreated(path)
+                | DirpStateMessage::PathRemoved(path)
+                | DirpStateMessage::PathModified(path) => {
+                    // Whatever happened to 'path', the dir holding it is what needs a rescan.
+                    if let Some(parent_path) = parent_file_path(&path) {
+                        if dirp_state.contains_key(&parent_path) {
+                            pending_rescan_set.insert(parent_path);
+                        }
+                    }
+                }
+                DirpStateMessage::RemoveMarked(remove_mode) => {
+                    if is_snapshot {
+                        print_snapshot_marked(&root_path, &dirp_state);
                     } else {
-                        panic!("shit");
+                        process_remove_marked(
+                            &root_path,
+                            remove_mode,
+                            size_mode,
+                            &scan_options,
+                            &dirp_state,
+                        )?;
                     }
+                    break;
+                }
+                DirpStateMessage::SavePlan(plan_path) => {
+                    let status_message =
+                        match save_plan(&root_path, size_mode, Some(&plan_path), &dirp_state) {
+                            Ok(()) => {
+                                format!("Saved the marked set to {}", plan_path.to_string_lossy())
+                            }
+                            Err(error) => format!("Saving the marked set failed: {}", error),
+                        };
+                    user_sender.send(UserMessage::Status(status_message))?;
                 }
-                DirpStateMessage::RemoveMarked => {
-                    process_remove_marked(&root_path, &dirp_state)?;
+                DirpStateMessage::WritePlan(plan_path) => {
+                    process_write_plan(&root_path, size_mode, plan_path.as_deref(), &dirp_state);
                     break;
                 }
                 DirpStateMessage::Quit => break,
@@ -122,12 +487,79 @@ pub fn dirp_state_loop(
             }
         }
     }
+
+    if let Some(scan_cache) = &scan_options.scan_cache {
+        if let Err(error) = scan_cache.save(scan_progress.is_complete) {
+            error!("Can not save the scan cache: {:?}", error);
+        }
+    }
     Ok(())
 }
 
+//
+// Logs each message the state thread handles (at debug, or trace for the frequent
+// ones), and passes it on.
+//
+fn log_message(message: DirpStateMessage) -> DirpStateMessage {
+    match &message {
+        DirpStateMessage::DirScanMessage(dir) => trace!(
+            "DirScanMessage {} ({} entries, {} errors)",
+            dir.path.display(),
+            dir.dir_obj_list.len(),
+            dir.error_list.len()
+        ),
+        DirpStateMessage::Flush => {}
+        DirpStateMessage::PathCreated(_)
+        | DirpStateMessage::PathRemoved(_)
+        | DirpStateMessage::PathModified(_) => trace!("{:?}", message),
+        _ => debug!("{:?}", message),
+    }
+    message
+}
+
+fn load_snapshot_dir(mut dir: Dir, dirp_state: &mut DirHash, scan_progress: &mut ScanProgress) {
+    // 'dir' is a full tree, read from a saved scan. It's stored the way scanned dirs
+    // are, with each sub dir in its own entry, referenced by a DirRef. The sizes are
+    // taken as saved.
+
+    let mut sub_dir_list = Vec::new();
+    for fs_obj in &mut dir.dir_obj_list {
+        match fs_obj {
+            FSObj::Dir(sub_dir) => {
+                let dir_ref = FSObj::DirRef(DirRef {
+                    path: sub_dir.path.clone(),
+                    is_open: false,
+                    size_in_bytes: 0,
+                    disk_size_in_bytes: 0,
+                    percent: 0,
+                    is_marked: sub_dir.is_marked,
+                    is_excluded: sub_dir.is_excluded,
+                    mtime: sub_dir.mtime,
+                });
+                sub_dir_list.push(std::mem::replace(fs_obj, dir_ref));
+            }
+            FSObj::File(_) => {
+                scan_progress.files_scanned += 1;
+            }
+            _ => {}
+        }
+    }
+    scan_progress.dirs_scanned += 1;
+    dirp_state.insert(dir.path.clone(), dir);
+
+    for sub_dir in sub_dir_list {
+        if let FSObj::Dir(sub_dir) = sub_dir {
+            load_snapshot_dir(sub_dir, dirp_state, scan_progress);
+        }
+    }
+}
+
 fn process_dir_scan_message(
     mut dir: Dir,
     dirp_state: &mut DirHash,
+    inode_hash: &mut InodeHash,
+    pending_scan_count: &mut usize,
+    scan_options: &Arc<ScanOptions>,
     dirp_state_sender: &Sender<DirpStateMessage>,
     threadpool: &ThreadPool,
 ) {
@@ -135,50 +567,496 @@ fn process_dir_scan_message(
 
     // Post process the dir.
     dir.size_in_bytes = 0;
-    for fs_obj in &dir.dir_obj_list {
+    dir.disk_size_in_bytes = 0;
+    for fs_obj in &mut dir.dir_obj_list {
         match fs_obj {
             FSObj::Dir(_) => {
                 assert!(false, "Invalid state.");
             }
-            FSObj::DirRef(dir_ref) => {
-                // Recurse
-                scan_dir_path_in_threadpool(
-                    dir_ref.path.clone(),
-                    false,
-                    dirp_state_sender.clone(),
-                    &threadpool,
-                );
-            }
-            FSObj::SymLink(_) => {
+            FSObj::DirRef(dir_ref) => match dirp_state.get(&dir_ref.path) {
+                Some(sub_dir) => {
+                    // Kept through a rescan of this dir (see remove_for_rescan()), so
+                    // already scanned and sized.
+                    dir_ref.is_marked = sub_dir.is_marked;
+                    if !sub_dir.is_excluded {
+                        dir.size_in_bytes += sub_dir.size_in_bytes;
+                        dir.disk_size_in_bytes += sub_dir.disk_size_in_bytes;
+                    }
+                }
+                None => {
+                    // Recurse
+                    *pending_scan_count += 1;
+                    scan_dir_path_in_threadpool(
+                        dir_ref.path.clone(),
+                        false,
+                        scan_options.clone(),
+                        dirp_state_sender.clone(),
+                        threadpool,
+                    );
+                }
+            },
+            FSObj::SymLink(_) | FSObj::MountPoint(_) => {
                 // Ignore
             }
             FSObj::File(file) => {
                 // Size the directory
-                dir.size_in_bytes += file.size_in_bytes;
+                if !file.is_excluded && claim_inode(file, inode_hash) {
+                    dir.size_in_bytes += file.size_in_bytes;
+                    dir.disk_size_in_bytes += file.disk_size_in_bytes;
+                }
             }
         }
     }
 
-    // Resize parent dirs.
-    let mut parent_path_opt = parent_file_path(&dir.path);
+    // Resize parent dirs. An excluded dir keeps its own size, but adds nothing to them.
+    if !dir.is_excluded {
+        resize_parent_dirs(
+            &dir.path,
+            dir.size_in_bytes as i64,
+            dir.disk_size_in_bytes as i64,
+            dirp_state,
+        );
+    }
+
+    // Update state.
+    dirp_state.insert(dir.path.clone(), dir);
+}
+
+fn resize_parent_dirs(
+    path: &Path,
+    delta_in_bytes: i64,
+    disk_delta_in_bytes: i64,
+    dirp_state: &mut DirHash,
+) {
+    // Stops at the first dir that isn't there. Whatever is above it was sized without
+    // it, and it adds its whole size in when it comes back.
+    let mut parent_path_opt = parent_file_path(path);
     while let Some(parent_path) = parent_path_opt {
-        if let Some(parent_dir) = dirp_state.get_mut(&parent_path) {
-            parent_dir.size_in_bytes += dir.size_in_bytes;
+        let parent_dir = match dirp_state.get_mut(&parent_path) {
+            Some(parent_dir) => parent_dir,
+            None => break,
+        };
+        parent_dir.size_in_bytes = parent_dir
+            .size_in_bytes
+            .saturating_add_signed(delta_in_bytes);
+        parent_dir.disk_size_in_bytes = parent_dir
+            .disk_size_in_bytes
+            .saturating_add_signed(disk_delta_in_bytes);
+        if parent_dir.is_excluded {
+            // Nothing above an excluded dir counts what is in it.
+            break;
         }
         parent_path_opt = parent_file_path(&parent_path);
     }
+}
 
-    // Update state.
-    dirp_state.insert(dir.path.clone(), dir);
+fn is_dir_listed_in_parent(path: &Path, dirp_state: &DirHash) -> bool {
+    // Only a dir's parent schedules its scan, so a dir its parent no longer lists has
+    // since been removed.
+    let parent_dir =
+        match parent_file_path(path).and_then(|parent_path| dirp_state.get(&parent_path)) {
+            Some(parent_dir) => parent_dir,
+            None => return false,
+        };
+    parent_dir.dir_obj_list.iter().any(|fs_obj| match fs_obj {
+        FSObj::DirRef(dir_ref) => dir_ref.path == *path,
+        _ => false,
+    })
+}
+
+fn inherit_mark(dir: &mut Dir, dirp_state: &DirHash) {
+    // 'dir' is new to 'dirp_state'. If its parent was marked before this scan came in,
+    // the mark was left on the DirRef, and now goes on everything in it.
+
+    let is_marked = parent_file_path(&dir.path)
+        .and_then(|parent_path| dirp_state.get(&parent_path))
+        .is_some_and(|parent_dir| {
+            parent_dir.dir_obj_list.iter().any(|fs_obj| match fs_obj {
+                FSObj::DirRef(dir_ref) => dir_ref.path == dir.path && dir_ref.is_marked,
+                _ => false,
+            })
+        });
+    if !is_marked {
+        return;
+    }
+    dir.is_marked = true;
+    for fs_obj in &mut dir.dir_obj_list {
+        match fs_obj {
+            FSObj::DirRef(dir_ref) => dir_ref.is_marked = true,
+            FSObj::File(file) => file.is_marked = true,
+            FSObj::SymLink(sym_link) => sym_link.is_marked = true,
+            _ => {}
+        }
+    }
 }
 
-fn process_remove_marked(root_path: &String, dirp_state: &DirHash) -> Result<(), DirpError> {
+//
+// What the user had done to a path (opened it, marked it) before it was dropped for a
+// rescan. Restored when the path turns up in the new scan.
+//
+struct KeptState {
+    is_open: bool,
+    is_marked: bool,
+}
+
+fn remove_for_rescan(
+    path: &Path,
+    is_deep: bool,
+    dirp_state: &mut DirHash,
+    inode_hash: &mut InodeHash,
+    kept_state_hash: &mut HashMap<PathBuf, KeptState>,
+) -> Option<Dir> {
+    // Drop the dir at 'path' ready for a fresh scan, and everything under it if
+    // 'is_deep'. Otherwise its sub dirs stay, and are taken back in, as they are, by the
+    // new scan (see process_dir_scan_message()). Its size comes out of every dir above
+    // it, and goes back in as the new scan comes in.
+
+    keep_state(path, is_deep, dirp_state, kept_state_hash);
+    let old_dir = match is_deep {
+        true => remove_dir_tree(path, dirp_state, inode_hash)?,
+        false => {
+            let old_dir = dirp_state.remove(path)?;
+            for fs_obj in &old_dir.dir_obj_list {
+                if let FSObj::File(file) = fs_obj {
+                    release_inode(file, inode_hash);
+                }
+            }
+            old_dir
+        }
+    };
+    if !old_dir.is_excluded {
+        resize_parent_dirs(
+            path,
+            -(old_dir.size_in_bytes as i64),
+            -(old_dir.disk_size_in_bytes as i64),
+            dirp_state,
+        );
+    }
+
+    Some(old_dir)
+}
+
+fn keep_state(
+    path: &Path,
+    is_deep: bool,
+    dirp_state: &DirHash,
+    kept_state_hash: &mut HashMap<PathBuf, KeptState>,
+) {
+    let dir = match dirp_state.get(path) {
+        Some(dir) => dir,
+        None => return,
+    };
+    kept_state_hash.insert(
+        path.to_path_buf(),
+        KeptState {
+            is_open: dir.is_open,
+            is_marked: dir.is_marked,
+        },
+    );
+    for fs_obj in &dir.dir_obj_list {
+        match fs_obj {
+            FSObj::DirRef(dir_ref) if is_deep => {
+                keep_state(&dir_ref.path, true, dirp_state, kept_state_hash)
+            }
+            FSObj::File(file) => {
+                kept_state_hash.insert(
+                    file.path.clone(),
+                    KeptState {
+                        is_open: false,
+                        is_marked: file.is_marked,
+                    },
+                );
+            }
+            FSObj::SymLink(sym_link) => {
+                kept_state_hash.insert(
+                    sym_link.path.clone(),
+                    KeptState {
+                        is_open: false,
+                        is_marked: sym_link.is_marked,
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+fn restore_kept_state(dir: &mut Dir, kept_state_hash: &mut HashMap<PathBuf, KeptState>) {
+    // 'dir' is new to 'dirp_state'. If it was dropped for a rescan, put back what the
+    // user had done to it, and to its entries.
+
+    let kept_state = match kept_state_hash.remove(&dir.path) {
+        Some(kept_state) => kept_state,
+        None => return,
+    };
+    dir.is_open = kept_state.is_open;
+    dir.is_marked = kept_state.is_marked;
+
+    for fs_obj in &mut dir.dir_obj_list {
+        // Anything new takes the dir's mark, as it would have on a rescan.
+        match fs_obj {
+            FSObj::DirRef(dir_ref) => {
+                // Removed once the sub dir's own scan comes in.
+                dir_ref.is_marked = kept_state_hash
+                    .get(&dir_ref.path)
+                    .map_or(dir.is_marked, |kept_state| kept_state.is_marked);
+            }
+            FSObj::File(file) => {
+                file.is_marked = kept_state_hash
+                    .remove(&file.path)
+                    .map_or(dir.is_marked, |kept_state| kept_state.is_marked);
+            }
+            FSObj::SymLink(sym_link) => {
+                sym_link.is_marked = kept_state_hash
+                    .remove(&sym_link.path)
+                    .map_or(dir.is_marked, |kept_state| kept_state.is_marked);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn remove_gone_sub_dirs(
+    old_dir: &Dir,
+    dir: &Dir,
+    dirp_state: &mut DirHash,
+    inode_hash: &mut InodeHash,
+) {
+    // 'dir' is a fresh scan of 'old_dir'. The sub dirs it no longer lists have been
+    // removed, or moved, since.
+
+    let sub_dir_path_set: HashSet<&PathBuf> = dir
+        .dir_obj_list
+        .iter()
+        .filter_map(|fs_obj| match fs_obj {
+            FSObj::DirRef(dir_ref) => Some(&dir_ref.path),
+            _ => None,
+        })
+        .collect();
+    for fs_obj in &old_dir.dir_obj_list {
+        if let FSObj::DirRef(dir_ref) = fs_obj {
+            if !sub_dir_path_set.contains(&dir_ref.path) {
+                remove_dir_tree(&dir_ref.path, dirp_state, inode_hash);
+            }
+        }
+    }
+}
+
+fn remove_dir_tree(
+    path: &Path,
+    dirp_state: &mut DirHash,
+    inode_hash: &mut InodeHash,
+) -> Option<Dir> {
+    // Remove 'path' and everything under it from 'dirp_state'. Returns the removed dir.
+
+    let dir = dirp_state.remove(path)?;
+    for fs_obj in &dir.dir_obj_list {
+        match fs_obj {
+            FSObj::DirRef(dir_ref) => {
+                remove_dir_tree(&dir_ref.path, dirp_state, inode_hash);
+            }
+            FSObj::File(file) => {
+                release_inode(file, inode_hash);
+            }
+            _ => {}
+        }
+    }
+
+    Some(dir)
+}
+
+//
+// Hard linked files share one inode, and so one set of blocks. Only the first link
+// to an inode seen in a scan is counted towards dir sizes, and 'inode_hash' records
+// which path that was. If that path goes away its claim is released, but the other
+// links to the inode aren't recounted until their own dirs are rescanned.
+//
+fn claim_inode(file: &File, inode_hash: &mut InodeHash) -> bool {
+    if file.nlink <= 1 {
+        return true;
+    }
+    match inode_hash.entry((file.dev, file.ino)) {
+        Entry::Occupied(entry) => *entry.get() == file.path,
+        Entry::Vacant(entry) => {
+            entry.insert(file.path.clone());
+            true
+        }
+    }
+}
+
+fn release_inode(file: &File, inode_hash: &mut InodeHash) -> bool {
+    // Returns true if 'file' had been counted.
+    if file.nlink <= 1 {
+        return true;
+    }
+    if inode_hash.get(&(file.dev, file.ino)) == Some(&file.path) {
+        inode_hash.remove(&(file.dev, file.ino));
+        true
+    } else {
+        false
+    }
+}
+
+fn build_marked_summary(
+    root_path: &Path,
+    size_mode: SizeMode,
+    dirp_state: &DirHash,
+) -> MarkedSummary {
+    // Total up what is marked, and what removing it would actually free. A hard linked
+    // file only frees its blocks when every link to it is removed.
+
+    let mut marked_summary = MarkedSummary::default();
+    let mut marked_inode_hash = HashMap::<(u64, u64), (u64, u64)>::new();
+
+    _build_marked_summary(
+        root_path,
+        false,
+        size_mode,
+        dirp_state,
+        &mut marked_summary,
+        &mut marked_inode_hash,
+    );
+
+    for (nlink, size_in_bytes) in marked_inode_hash.values() {
+        if *nlink == 0 {
+            marked_summary.reclaimable_size_in_bytes += size_in_bytes;
+        }
+    }
+
+    marked_summary
+}
+
+fn _build_marked_summary(
+    path: &Path,
+    is_parent_marked: bool,
+    size_mode: SizeMode,
+    dirp_state: &DirHash,
+    marked_summary: &mut MarkedSummary,
+    marked_inode_hash: &mut HashMap<(u64, u64), (u64, u64)>,
+) {
+    // 'marked_inode_hash' maps each marked, hard linked inode to the number of its links
+    // that are not marked, and its size.
+
+    if let Some(dir) = dirp_state.get(path) {
+        let is_dir_marked = is_parent_marked || dir.is_marked;
+        for child in &dir.dir_obj_list {
+            match child {
+                FSObj::Dir(_) => {
+                    panic!("Internal Error");
+                }
+                FSObj::DirRef(dir_ref) => {
+                    _build_marked_summary(
+                        &dir_ref.path,
+                        is_dir_marked || dir_ref.is_marked,
+                        size_mode,
+                        dirp_state,
+                        marked_summary,
+                        marked_inode_hash,
+                    );
+                }
+                FSObj::File(file) => {
+                    if is_dir_marked || file.is_marked {
+                        let size_in_bytes = file.size_in_bytes_for(size_mode);
+                        marked_summary.size_in_bytes += size_in_bytes;
+                        if file.nlink <= 1 {
+                            marked_summary.reclaimable_size_in_bytes += size_in_bytes;
+                        } else {
+                            let (unmarked_nlink, _) = marked_inode_hash
+                                .entry((file.dev, file.ino))
+                                .or_insert((file.nlink, size_in_bytes));
+                            *unmarked_nlink -= 1;
+                        }
+                    }
+                }
+                FSObj::SymLink(sym_link) => {
+                    if is_dir_marked || sym_link.is_marked {
+                        let size_in_bytes = sym_link.size_in_bytes_for(size_mode);
+                        marked_summary.size_in_bytes += size_in_bytes;
+                        marked_summary.reclaimable_size_in_bytes += size_in_bytes;
+                    }
+                }
+                FSObj::MountPoint(_) => {
+                    // Never removed.
+                }
+            }
+        }
+    }
+}
+
+fn print_snapshot_marked(root_path: &Path, dirp_state: &DirHash) {
+    // The paths in a saved scan may not exist here, or may be something else by now,
+    // so nothing is removed. The list is still worth having.
+    println!();
+    for marked_file in marked_files_list(root_path, dirp_state) {
+        println!("{}", marked_file.display());
+    }
+    println!();
+    println!("This is a saved scan, so nothing has been moved to the Trash.");
+}
+
+fn save_plan(
+    root_path: &Path,
+    size_mode: SizeMode,
+    plan_path: Option<&Path>,
+    dirp_state: &DirHash,
+) -> Result<(), DirpError> {
+    let plan_entry_list = marked_plan_entry_list(root_path, size_mode, dirp_state);
+    let marked_summary = build_marked_summary(root_path, size_mode, dirp_state);
+    write_plan(
+        root_path,
+        size_mode,
+        &marked_summary,
+        &plan_entry_list,
+        plan_path,
+    )
+}
+
+fn process_write_plan(
+    root_path: &Path,
+    size_mode: SizeMode,
+    plan_path: Option<&Path>,
+    dirp_state: &DirHash,
+) {
+    // A dry run. Nothing is removed.
+    match save_plan(root_path, size_mode, plan_path, dirp_state) {
+        Ok(()) => {
+            if let Some(plan_path) = plan_path {
+                println!(
+                    "Wrote the removal plan to {}. Nothing was removed.",
+                    plan_path.to_string_lossy()
+                );
+            }
+        }
+        Err(error) => eprintln!("dirp: Can not write the removal plan: {}", error),
+    }
+}
+
+fn process_remove_marked(
+    root_path: &Path,
+    remove_mode: RemoveMode,
+    size_mode: SizeMode,
+    scan_options: &ScanOptions,
+    dirp_state: &DirHash,
+) -> Result<(), DirpError> {
+    let marked_files_list = marked_files_list(root_path, dirp_state);
     println!("");
-    for marked_file in marked_files_list(&root_path, &dirp_state) {
-        println!("{}", marked_file);
+    for marked_file in &marked_files_list {
+        println!("{}", marked_file.display());
     }
     println!("");
-    println!("Move these files to the Trash?");
+    match remove_mode {
+        RemoveMode::Trash => println!("Move these files to the Trash?"),
+        RemoveMode::Permanent => {
+            // The exact number, so there's no doubt about what is about to go.
+            let marked_summary = build_marked_summary(root_path, size_mode, dirp_state);
+            println!(
+                "Permanently delete these files, destroying {} bytes ({})? This can't be undone.",
+                marked_summary.reclaimable_size_in_bytes,
+                human_readable_bytes(marked_summary.reclaimable_size_in_bytes)
+            );
+            println!("{}", LEFT_OUT_NOTE);
+        }
+    }
 
     let items = vec!["No", "Yes"];
     let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
@@ -189,8 +1067,21 @@ fn process_remove_marked(root_path: &String, dirp_state: &DirHash) -> Result<(),
     match selection {
         Some(index) => {
             if index == 1 {
-                if let Err(error) = remove_marked_files(root_path.clone(), &dirp_state) {
-                    panic!("Error Removing Files: {:#?}", error);
+                let result = match remove_mode {
+                    RemoveMode::Trash => {
+                        let result = remove_marked_files(root_path, dirp_state);
+                        // Even if it failed part way, some of it may be in the Trash.
+                        if let Err(error) = record_session(&marked_files_list) {
+                            error!("Can not record what was moved to the Trash: {:?}", error);
+                        }
+                        result
+                    }
+                    RemoveMode::Permanent => delete_paths(&marked_files_list, scan_options)
+                        .map(|kept_path_list| print_kept_path_list(&kept_path_list)),
+                };
+                if let Err(error) = result {
+                    error!("Error removing files: {:?}", error);
+                    println!("Not everything could be removed: {}", error);
                 }
             }
         }
@@ -199,14 +1090,168 @@ fn process_remove_marked(root_path: &String, dirp_state: &DirHash) -> Result<(),
     Ok(())
 }
 
-fn build_result_tree(path: &String, include_all: bool, dirp_state: &DirHash) -> Dir {
+fn build_full_state_response(
+    root_path: &Path,
+    size_mode: SizeMode,
+    scan_progress: ScanProgress,
+    scan_error_list: Vec<ScanError>,
+    dirp_state: &DirHash,
+) -> GetStateResponse {
+    // The whole tree, open or not. Marks play no part in a full state request.
+    GetStateResponse {
+        dirp_state: build_result_tree(root_path, true, size_mode, dirp_state),
+        size_mode,
+        marked_summary: MarkedSummary::default(),
+        scan_progress,
+        scan_error_list,
+    }
+}
+
+fn build_scan_error_list(
+    error_dir_set: &mut HashSet<PathBuf>,
+    dirp_state: &DirHash,
+) -> Vec<ScanError> {
+    // 'error_dir_set' holds every dir that has had errors. Those that have since been
+    // removed, or rescanned without errors, are dropped from it here.
+    error_dir_set.retain(|path| {
+        dirp_state
+            .get(path)
+            .is_some_and(|dir| !dir.error_list.is_empty())
+    });
+
+    let mut scan_error_list = Vec::new();
+    for path in error_dir_set.iter() {
+        if let Some(dir) = dirp_state.get(path) {
+            scan_error_list.extend(dir.error_list.iter().cloned());
+        }
+    }
+    scan_error_list.sort_by(|a, b| a.path.cmp(&b.path));
+
+    scan_error_list
+}
+
+//
+// What a filter leaves shown: every path whose name matches it, and the dirs that lead
+// to them. Kept up to date as dirs are listed and removed (see list_dir()), rather than
+// worked out from the whole tree for each update. The dirs that lead to a match are
+// shown open, whatever the user has done to them, and keep their full sizes.
+//
+struct Filter {
+    query: String,
+    // The entries of each dir that match.
+    match_hash: HashMap<PathBuf, Vec<PathBuf>>,
+    // Each path shown, with how many matches it is, or leads to. The root is always shown.
+    shown_count_hash: HashMap<PathBuf, usize>,
+    // The paths shown, or no longer shown, since the last take_filter_change().
+    changed_path_set: HashSet<PathBuf>,
+}
+
+impl Filter {
+    fn new(
+        query: String,
+        root_path: &Path,
+        matcher: &SkimMatcherV2,
+        dirp_state: &DirHash,
+    ) -> Filter {
+        let mut filter = Filter {
+            query,
+            match_hash: HashMap::new(),
+            shown_count_hash: HashMap::from([(root_path.to_path_buf(), 1)]),
+            changed_path_set: HashSet::new(),
+        };
+        for dir in dirp_state.values() {
+            filter.list_dir(root_path, dir, matcher);
+        }
+        filter.changed_path_set.clear();
+
+        filter
+    }
+
+    //
+    // 'dir' has been listed, or listed again. Its entries that match now take the place
+    // of those that did.
+    //
+    fn list_dir(&mut self, root_path: &Path, dir: &Dir, matcher: &SkimMatcherV2) {
+        self.remove_dir(root_path, &dir.path);
+        let match_list: Vec<PathBuf> = dir
+            .dir_obj_list
+            .iter()
+            .map(fs_obj_path)
+            .filter(|path| is_name_match(matcher, path, &self.query))
+            .map(Path::to_path_buf)
+            .collect();
+        for path in &match_list {
+            for shown_path in path
+                .ancestors()
+                .take_while(|path| path.starts_with(root_path))
+            {
+                let shown_count = self
+                    .shown_count_hash
+                    .entry(shown_path.to_path_buf())
+                    .or_insert(0);
+                *shown_count += 1;
+                if *shown_count == 1 {
+                    self.changed_path_set.insert(shown_path.to_path_buf());
+                }
+            }
+        }
+        if !match_list.is_empty() {
+            self.match_hash.insert(dir.path.clone(), match_list);
+        }
+    }
+
+    fn remove_dir(&mut self, root_path: &Path, dir_path: &Path) {
+        for path in self.match_hash.remove(dir_path).unwrap_or_default() {
+            for shown_path in path
+                .ancestors()
+                .take_while(|path| path.starts_with(root_path))
+            {
+                if let Some(shown_count) = self.shown_count_hash.get_mut(shown_path) {
+                    *shown_count -= 1;
+                    if *shown_count == 0 {
+                        self.shown_count_hash.remove(shown_path);
+                        self.changed_path_set.insert(shown_path.to_path_buf());
+                    }
+                }
+            }
+        }
+    }
+
+    fn shown_path_list(&self) -> Vec<PathBuf> {
+        self.shown_count_hash.keys().cloned().collect()
+    }
+
+    fn take_filter_change(&mut self) -> Option<FilterChange> {
+        if self.changed_path_set.is_empty() {
+            return None;
+        }
+        let (shown_path_list, hidden_path_list) = self
+            .changed_path_set
+            .drain()
+            .partition(|path| self.shown_count_hash.contains_key(path));
+
+        Some(FilterChange::Changed {
+            shown_path_list,
+            hidden_path_list,
+        })
+    }
+}
+
+fn build_result_tree(
+    path: &Path,
+    include_all: bool,
+    size_mode: SizeMode,
+    dirp_state: &DirHash,
+) -> Dir {
     let root_dir = dirp_state.get(path).expect("internal error");
-    _build_result_tree(path, include_all, dirp_state, root_dir.size_in_bytes as f64)
+    let total_bytes = root_dir.size_in_bytes_for(size_mode) as f64;
+    _build_result_tree(path, include_all, size_mode, dirp_state, total_bytes)
 }
 
 fn _build_result_tree(
-    path: &String,
+    path: &Path,
     include_all: bool,
+    size_mode: SizeMode,
     dirp_state: &DirHash,
     total_bytes: f64,
 ) -> Dir {
@@ -216,7 +1261,8 @@ fn _build_result_tree(
     let result_dir = dirp_state.get(path);
     if let Some(result_dir) = result_dir {
         let mut result_dir = result_dir.clone();
-        result_dir.percent = ((result_dir.size_in_bytes as f64 / total_bytes) * 100.0) as u8;
+        result_dir.percent =
+            ((result_dir.size_in_bytes_for(size_mode) as f64 / total_bytes) * 100.0) as u8;
 
         let mut new_dir_obj_list = Vec::<FSObj>::new();
         if result_dir.is_open || include_all {
@@ -229,22 +1275,26 @@ fn _build_result_tree(
                         new_dir_obj_list.push(FSObj::Dir(_build_result_tree(
                             &dir_ref.path,
                             include_all,
+                            size_mode,
                             dirp_state,
                             total_bytes,
                         )));
                     }
                     FSObj::File(fs_obj) => {
                         let mut fs_obj = fs_obj.clone();
-                        fs_obj.percent =
-                            ((fs_obj.size_in_bytes as f64 / total_bytes) * 100.0) as u8;
+                        let size_in_bytes = fs_obj.size_in_bytes_for(size_mode);
+                        fs_obj.percent = ((size_in_bytes as f64 / total_bytes) * 100.0) as u8;
                         new_dir_obj_list.push(FSObj::File(fs_obj));
                     }
                     FSObj::SymLink(fs_obj) => {
                         let mut fs_obj = fs_obj.clone();
-                        fs_obj.percent =
-                            ((fs_obj.size_in_bytes as f64 / total_bytes) * 100.0) as u8;
+                        let size_in_bytes = fs_obj.size_in_bytes_for(size_mode);
+                        fs_obj.percent = ((size_in_bytes as f64 / total_bytes) * 100.0) as u8;
                         new_dir_obj_list.push(FSObj::SymLink(fs_obj));
                     }
+                    FSObj::MountPoint(fs_obj) => {
+                        new_dir_obj_list.push(FSObj::MountPoint(fs_obj.clone()));
+                    }
                 }
             }
         }
@@ -253,17 +1303,21 @@ fn _build_result_tree(
         result_dir
     } else {
         Dir {
-            path: path.clone(),
+            path: path.to_path_buf(),
             size_in_bytes: 0,
+            disk_size_in_bytes: 0,
             percent: 0,
             is_marked: false,
+            is_excluded: false,
+            mtime: 0,
             is_open: false,
             dir_obj_list: FSObjList::new(),
+            error_list: Vec::new(),
         }
     }
 }
 
-fn is_path_marked(path: &String, dirp_state: &DirHash) -> Option<bool> {
+fn is_path_marked(path: &Path, dirp_state: &DirHash) -> Option<bool> {
     if let Some(dir) = dirp_state.get(path) {
         Some(dir.is_marked)
     } else {
@@ -290,19 +1344,40 @@ fn is_path_marked(path: &String, dirp_state: &DirHash) -> Option<bool> {
                         return Some(obj.is_marked);
                     }
                 }
+                FSObj::MountPoint(obj) => {
+                    if obj.path == *path {
+                        return Some(false);
+                    }
+                }
             }
         }
         None
     }
 }
 
-fn do_mark_deep(path: &String, is_marked: bool, dirp_state: &mut DirHash) {
-    if let None = _do_mark_deep(path, is_marked, dirp_state) {
-        assert!(false, "Internal error in do_mark_deep");
+fn do_mark_deep(
+    path: &Path,
+    is_marked: bool,
+    dirp_state: &mut DirHash,
+    kept_state_hash: &mut HashMap<PathBuf, KeptState>,
+) {
+    // Anything under 'path' that is out for a rescan takes the mark when it's back,
+    // rather than the one it had when it went.
+    let mut is_kept = false;
+    for (kept_path, kept_state) in kept_state_hash.iter_mut() {
+        if kept_path.starts_with(path) {
+            kept_state.is_marked = is_marked;
+            is_kept = true;
+        }
+    }
+
+    if _do_mark_deep(path, is_marked, dirp_state).is_none() && !is_kept {
+        // e.g. dropped for a rescan since the UI last showed it.
+        warn!("Can not mark {}: it's not in the tree.", path.display());
     }
 }
 
-fn _do_mark_deep(path: &String, is_marked: bool, dirp_state: &mut DirHash) -> Option<()> {
+fn _do_mark_deep(path: &Path, is_marked: bool, dirp_state: &mut DirHash) -> Option<()> {
     // Mark all objects as 'is_marked' from 'path' all the way down the tree.
 
     // Find 'path' in 'dirp_state'.
@@ -311,7 +1386,7 @@ fn _do_mark_deep(path: &String, is_marked: bool, dirp_state: &mut DirHash) -> Op
 
         dir.is_marked = is_marked;
         let mut child_path_list = Vec::new();
-        for child in &mut dir.dir_obj_list {
+        for child in &dir.dir_obj_list {
             match child {
                 FSObj::Dir(dir) => {
                     child_path_list.push(dir.path.clone());
@@ -325,6 +1400,9 @@ fn _do_mark_deep(path: &String, is_marked: bool, dirp_state: &mut DirHash) -> Op
                 FSObj::SymLink(sym_link) => {
                     child_path_list.push(sym_link.path.clone());
                 }
+                FSObj::MountPoint(_) => {
+                    // Mount points can't be marked.
+                }
             }
         }
         for child_path in child_path_list {
@@ -333,95 +1411,233 @@ fn _do_mark_deep(path: &String, is_marked: bool, dirp_state: &mut DirHash) -> Op
 
         Some(())
     } else {
-        // path must be a file or a sym_link.
+        // path must be a file, a sym_link, or a dir not scanned yet.
         // Look for parent, then search parent.
 
-        let parent_dir = dirp_state.get_mut(&parent_file_path(path)?)?;
-        for child in &mut parent_dir.dir_obj_list {
-            match child {
-                FSObj::File(file) => {
-                    if file.path == *path {
-                        // 'path' is a file.
-                        file.is_marked = is_marked;
-
-                        return Some(());
-                    }
-                }
-                FSObj::SymLink(sym_link) => {
-                    if sym_link.path == *path {
-                        // 'path' is a sym link.
-                        sym_link.is_marked = is_marked;
-
-                        return Some(());
-                    }
-                }
-                _ => {
-                    // Do nothing.
-                }
+        match dirp_state.get_entry_mut(&parent_file_path(path)?, path)? {
+            FSObj::File(file) => {
+                // 'path' is a file.
+                file.is_marked = is_marked;
+            }
+            FSObj::SymLink(sym_link) => {
+                // 'path' is a sym link.
+                sym_link.is_marked = is_marked;
+            }
+            FSObj::DirRef(dir_ref) => {
+                // 'path' is a dir that hasn't been scanned yet. It takes the mark
+                // when it is.
+                dir_ref.is_marked = is_marked;
+            }
+            FSObj::MountPoint(_) | FSObj::Dir(_) => {
+                // 'path' is a mount point, which can't be marked.
             }
         }
 
-        assert!(false, "path {:#?} not found in dirp_state.", &path,);
-        None
+        Some(())
     }
 }
 
-fn marked_files_list(path: &String, dirp_state: &DirHash) -> Vec<String> {
-    let mut marked_files_list = Vec::new();
+fn marked_files_list(path: &Path, dirp_state: &DirHash) -> Vec<PathBuf> {
+    marked_plan_entry_list(path, SizeMode::Apparent, dirp_state)
+        .into_iter()
+        .map(|plan_entry| plan_entry.path)
+        .collect()
+}
 
-    _marked_files_list(
-        &build_result_tree(&path, true, dirp_state),
-        &mut marked_files_list,
+fn marked_plan_entry_list(
+    path: &Path,
+    size_mode: SizeMode,
+    dirp_state: &DirHash,
+) -> Vec<PlanEntry> {
+    let mut plan_entry_list = Vec::new();
+
+    _marked_plan_entry_list(
+        &build_result_tree(path, true, size_mode, dirp_state),
+        size_mode,
+        &mut plan_entry_list,
     );
-    marked_files_list.sort();
+    plan_entry_list.sort_by(|a, b| a.path.cmp(&b.path));
 
-    marked_files_list
+    plan_entry_list
 }
 
-fn _marked_files_list(dir: &Dir, marked_files_list: &mut Vec<String>) {
+fn _marked_plan_entry_list(dir: &Dir, size_mode: SizeMode, plan_entry_list: &mut Vec<PlanEntry>) {
     if dir.is_marked {
-        marked_files_list.push(dir.path.clone());
+        plan_entry_list.push(PlanEntry {
+            path: dir.path.clone(),
+            size_in_bytes: plan_dir_size(dir, size_mode, &mut HashSet::new()),
+            mtime: dir.mtime,
+        });
     } else {
         for child in &dir.dir_obj_list {
             match child {
                 FSObj::Dir(child_dir) => {
-                    _marked_files_list(&child_dir, marked_files_list);
+                    _marked_plan_entry_list(child_dir, size_mode, plan_entry_list);
                 }
-                FSObj::DirRef(_dir_ref) => {
-                    panic!("Internal Error");
+                FSObj::DirRef(dir_ref) => {
+                    // build_result_tree() makes every dir a Dir, so this shouldn't happen.
+                    error!(
+                        "{} is missing from the marked file list.",
+                        dir_ref.path.display()
+                    );
                 }
                 FSObj::File(file) => {
                     if file.is_marked {
-                        marked_files_list.push(file.path.clone());
+                        plan_entry_list.push(PlanEntry {
+                            path: file.path.clone(),
+                            size_in_bytes: file.size_in_bytes_for(size_mode),
+                            mtime: file.mtime,
+                        });
                     }
                 }
                 FSObj::SymLink(sym_link) => {
                     if sym_link.is_marked {
-                        marked_files_list.push(sym_link.path.clone());
+                        plan_entry_list.push(PlanEntry {
+                            path: sym_link.path.clone(),
+                            size_in_bytes: sym_link.size_in_bytes_for(size_mode),
+                            mtime: sym_link.mtime,
+                        });
                     }
                 }
+                FSObj::MountPoint(_) => {}
             }
         }
     }
 }
 
-fn remove_marked_files(path: String, dirp_state: &DirHash) -> Result<(), DirpError> {
+//
+// The size of 'dir' as 'dirp apply' measures it (see plan::dir_size()), so that a plan
+// entry only drifts if the dir does. The scan counts a hard linked file towards whichever
+// dir claimed its inode first, anywhere in the tree, which can't be known again later.
+// Here it's counted once for the dir it's under ('inode_set'), wherever else it's linked.
+//
+fn plan_dir_size(dir: &Dir, size_mode: SizeMode, inode_set: &mut HashSet<(u64, u64)>) -> u64 {
+    let mut size_in_bytes = 0;
+    for child in &dir.dir_obj_list {
+        match child {
+            FSObj::Dir(child_dir) => {
+                if !child_dir.is_excluded {
+                    size_in_bytes += plan_dir_size(child_dir, size_mode, inode_set);
+                }
+            }
+            FSObj::File(file) => {
+                if !file.is_excluded && (file.nlink <= 1 || inode_set.insert((file.dev, file.ino)))
+                {
+                    size_in_bytes += file.size_in_bytes_for(size_mode);
+                }
+            }
+            FSObj::DirRef(_) | FSObj::SymLink(_) | FSObj::MountPoint(_) => {}
+        }
+    }
+    size_in_bytes
+}
+
+fn remove_marked_files(path: &Path, dirp_state: &DirHash) -> Result<(), DirpError> {
     let mut marked_files_list = Vec::new();
 
     _remove_marked_files(
-        FSObj::Dir(build_result_tree(&path, true, dirp_state)),
+        FSObj::Dir(build_result_tree(
+            path,
+            true,
+            SizeMode::Apparent,
+            dirp_state,
+        )),
         &mut marked_files_list,
     )?;
 
+    for path in &marked_files_list {
+        info!("Moving {} to the Trash", path.display());
+    }
     trash::delete_all(marked_files_list)?;
 
     Ok(())
 }
 
-fn _remove_marked_files(obj: FSObj, marked_files_list: &mut Vec<String>) -> Result<(), DirpError> {
+// Said before anything is deleted for good.
+pub const LEFT_OUT_NOTE: &str =
+    "Anything in them the scan left out (excluded, or with -x on another file system) is kept.";
+
+//
+// Deletes each of 'path_list' for good. A symlink is removed, not what it points to.
+//
+// Dirs are walked, rather than removed whole, so what the scan left out of them, and so
+// out of what the user was shown, stays: anything the exclude rules leave out and, with
+// '-x', anything on another file system than the path it's under. Those, and the dirs
+// holding them, are left in place. Returns what was kept.
+//
+pub fn delete_paths(
+    path_list: &[PathBuf],
+    scan_options: &ScanOptions,
+) -> Result<Vec<PathBuf>, DirpError> {
+    let mut kept_path_list = Vec::new();
+    for path in path_list {
+        info!("Deleting {}", path.display());
+        let dev = fs::symlink_metadata(path)?.st_dev();
+        delete_path(path, dev, scan_options, &mut kept_path_list)?;
+    }
+
+    Ok(kept_path_list)
+}
+
+fn delete_path(
+    path: &Path,
+    dev: u64,
+    scan_options: &ScanOptions,
+    kept_path_list: &mut Vec<PathBuf>,
+) -> Result<bool, DirpError> {
+    // Returns whether 'path' is gone.
+    let meta_data = fs::symlink_metadata(path)?;
+    if !meta_data.is_dir() {
+        fs::remove_file(path)?;
+        return Ok(true);
+    }
+    if scan_options.root_dev.is_some() && meta_data.st_dev() != dev {
+        warn!(
+            "Not deleting {}: it's on another file system.",
+            path.display()
+        );
+        kept_path_list.push(path.to_path_buf());
+        return Ok(false);
+    }
+
+    let ignore_file_chain = scan_options.exclude_rules.ignore_file_chain(path);
+    let mut is_emptied = true;
+    for dir_entry in fs::read_dir(path)? {
+        let entry_path = dir_entry?.path();
+        let is_dir = fs::symlink_metadata(&entry_path)?.is_dir();
+        if scan_options
+            .exclude_rules
+            .is_excluded(&entry_path, is_dir, &ignore_file_chain)
+        {
+            warn!("Not deleting {}: it's excluded.", entry_path.display());
+            kept_path_list.push(entry_path);
+            is_emptied = false;
+        } else {
+            is_emptied &= delete_path(&entry_path, dev, scan_options, kept_path_list)?;
+        }
+    }
+    if is_emptied {
+        fs::remove_dir(path)?;
+    }
+
+    Ok(is_emptied)
+}
+
+pub fn print_kept_path_list(kept_path_list: &[PathBuf]) {
+    if kept_path_list.is_empty() {
+        return;
+    }
+    println!("Kept, as the scan left them out:");
+    for path in kept_path_list {
+        println!("{}", path.display());
+    }
+}
+
+fn _remove_marked_files(obj: FSObj, marked_files_list: &mut Vec<PathBuf>) -> Result<(), DirpError> {
     match obj {
         FSObj::Dir(dir) => {
             if dir.is_marked {
+                info!("Moving {} to the Trash", dir.path.display());
                 trash::delete(&dir.path)?;
             } else {
                 for child in dir.dir_obj_list {
@@ -429,8 +1645,9 @@ fn _remove_marked_files(obj: FSObj, marked_files_list: &mut Vec<String>) -> Resu
                 }
             }
         }
-        FSObj::DirRef(_dir_ref) => {
-            panic!("Internal Error");
+        FSObj::DirRef(dir_ref) => {
+            // build_result_tree() makes every dir a Dir, so this shouldn't happen.
+            error!("{} was left out of the removal.", dir_ref.path.display());
         }
         FSObj::File(file) => {
             if file.is_marked {
@@ -442,6 +1659,7 @@ fn _remove_marked_files(obj: FSObj, marked_files_list: &mut Vec<String>) -> Resu
                 marked_files_list.push(sym_link.path)
             }
         }
+        FSObj::MountPoint(_) => {}
     }
 
     Ok(())
@@ -450,52 +1668,752 @@ fn _remove_marked_files(obj: FSObj, marked_files_list: &mut Vec<String>) -> Resu
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
+    use crate::exclude::ExcludeRules;
+
+    fn find_fs_obj(path: impl AsRef<Path>, dir: &Dir) -> Option<&FSObj> {
+        let path = path.as_ref();
+        for child in &dir.dir_obj_list {
+            match child {
+                FSObj::Dir(child_dir) => {
+                    if child_dir.path == path {
+                        return Some(child);
+                    } else if let Some(fs_obj) = find_fs_obj(path, child_dir) {
+                        return Some(fs_obj);
+                    }
+                }
+                FSObj::DirRef(obj) => {
+                    if obj.path == path {
+                        return Some(child);
+                    }
+                }
+                FSObj::File(obj) => {
+                    if obj.path == path {
+                        return Some(child);
+                    }
+                }
+                FSObj::SymLink(obj) => {
+                    if obj.path == path {
+                        return Some(child);
+                    }
+                }
+                FSObj::MountPoint(obj) => {
+                    if obj.path == path {
+                        return Some(child);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn recv_state(
+        dirp_state: &DirpState,
+        root_path: &Path,
+        dir_hash: &mut DirHash,
+    ) -> GetStateResponse {
+        // Brings 'dir_hash' up to date with the next update, as the UI does, and returns
+        // the tree that shows.
+        loop {
+            if let UserMessage::StateUpdate(state_update) = dirp_state.recv() {
+                for dir_change in state_update.dir_change_list {
+                    dir_hash.apply(dir_change);
+                }
+                let mut error_dir_set = dir_hash.values().map(|dir| dir.path.clone()).collect();
+                return GetStateResponse {
+                    dirp_state: build_result_tree(
+                        root_path,
+                        false,
+                        state_update.size_mode,
+                        dir_hash,
+                    ),
+                    size_mode: state_update.size_mode,
+                    marked_summary: state_update.marked_summary,
+                    scan_progress: state_update.scan_progress,
+                    scan_error_list: build_scan_error_list(&mut error_dir_set, dir_hash),
+                };
+            }
+        }
+    }
+
+    fn recv_complete_state(
+        dirp_state: &DirpState,
+        root_path: &Path,
+        dir_hash: &mut DirHash,
+    ) -> GetStateResponse {
+        // State goes out as the scan runs. Wait for the one with every size final.
+        loop {
+            let state_response = recv_state(dirp_state, root_path, dir_hash);
+            if state_response.scan_progress.is_complete {
+                return state_response;
+            }
+        }
+    }
+
+    fn is_fs_obj_marked(path: impl AsRef<Path>, dir: &Dir) -> bool {
+        let path = path.as_ref();
+        match find_fs_obj(path, dir) {
+            Some(FSObj::Dir(obj)) => obj.is_marked,
+            Some(FSObj::DirRef(obj)) => obj.is_marked,
+            Some(FSObj::File(obj)) => obj.is_marked,
+            Some(FSObj::SymLink(obj)) => obj.is_marked,
+            Some(FSObj::MountPoint(_)) => false,
+            None => panic!("{} not found.", path.display()),
+        }
+    }
 
     #[test]
     fn test_dirp_state_task() -> Result<(), DirpError> {
-        let dirp_state = DirpState::new(String::from("./test"));
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+
+        let root_path = Path::new("./test");
+        let mut dir_hash = DirHash::new();
 
         // Test initial dirp state.
         println!("Test initial dirp state.");
-        if let UserMessage::GetStateResponse(state_response) = dirp_state.recv() {
-            let expected_hash = 5806868005216161309 as u64;
-            let mut hasher = DefaultHasher::new();
+        {
+            let state_response = recv_complete_state(&dirp_state, root_path, &mut dir_hash);
             let dir = state_response.dirp_state;
-            dir.hash(&mut hasher);
-            let hash = hasher.finish();
-
             println!("{:#?}", dir);
-            println!("Hash: {}", hash);
 
-            assert_eq!(expected_hash, hash, "Error: Unexpected result.");
-        } else {
-            assert!(false, "Unexpected user message.");
+            // Eight 1010 byte files. The sym link isn't counted.
+            assert_eq!(dir.size_in_bytes, 8080, "Error: Unexpected result.");
+            assert_eq!(dir.percent, 100, "Error: Unexpected result.");
+
+            let mut path_list: Vec<PathBuf> = dir
+                .dir_obj_list
+                .iter()
+                .map(|fs_obj| match fs_obj {
+                    FSObj::Dir(obj) => obj.path.clone(),
+                    FSObj::DirRef(obj) => obj.path.clone(),
+                    FSObj::File(obj) => obj.path.clone(),
+                    FSObj::SymLink(obj) => obj.path.clone(),
+                    FSObj::MountPoint(obj) => obj.path.clone(),
+                })
+                .collect();
+            path_list.sort();
+            assert_eq!(
+                path_list,
+                vec![
+                    PathBuf::from("./test/0.txt"),
+                    PathBuf::from("./test/0.txt.lnk"),
+                    PathBuf::from("./test/a"),
+                    PathBuf::from("./test/c"),
+                    PathBuf::from("./test/e")
+                ],
+                "Error: Unexpected result."
+            );
+
+            let scan_progress = state_response.scan_progress;
+            assert_eq!(scan_progress.dirs_scanned, 6, "Error: Unexpected result.");
+            assert_eq!(scan_progress.files_scanned, 8, "Error: Unexpected result.");
+            assert_eq!(
+                scan_progress.size_in_bytes, 8080,
+                "Error: Unexpected result."
+            );
+            assert_eq!(
+                scan_progress.pending_scan_count, 0,
+                "Error: Unexpected result."
+            );
         }
 
         // Toggle ./test/e and ./test/e/f open, then mark ./test/e and test result.
         println!("Toggle ./test/e and ./test/e/f open, then mark ./test/e and test result.");
-        dirp_state.send(DirpStateMessage::OpenDir(String::from("./test/e")));
-        dirp_state.send(DirpStateMessage::OpenDir(String::from("./test/e/f")));
-        dirp_state.send(DirpStateMessage::MarkPath(String::from("./test/e")));
-        if let UserMessage::GetStateResponse(state_response) = dirp_state.recv() {
-            let expected_hash = 3663658119498061311 as u64;
-            let mut hasher = DefaultHasher::new();
+        dirp_state.send(DirpStateMessage::OpenDir(PathBuf::from("./test/e")));
+        dirp_state.send(DirpStateMessage::OpenDir(PathBuf::from("./test/e/f")));
+        dirp_state.send(DirpStateMessage::MarkPath(PathBuf::from("./test/e")));
+        {
+            let state_response = recv_state(&dirp_state, root_path, &mut dir_hash);
             let dir = state_response.dirp_state;
-            dir.hash(&mut hasher);
-            let hash = hasher.finish();
-
             println!("{:#?}", dir);
-            println!("Hash: {}", hash);
 
-            assert_eq!(expected_hash, hash, "Error: Unexpected result.");
-        } else {
-            assert!(false, "Unexpected user message 2.");
+            if let Some(FSObj::Dir(e_dir)) = find_fs_obj("./test/e", &dir) {
+                assert!(e_dir.is_open, "Error: Unexpected result.");
+                assert_eq!(e_dir.size_in_bytes, 2020, "Error: Unexpected result.");
+            } else {
+                assert!(false, "Error: ./test/e not found.");
+            }
+            assert!(is_fs_obj_marked("./test/e", &dir));
+            assert!(is_fs_obj_marked("./test/e/6.txt", &dir));
+            assert!(is_fs_obj_marked("./test/e/f", &dir));
+            assert!(is_fs_obj_marked("./test/e/f/7.txt", &dir));
+            assert!(!is_fs_obj_marked("./test/0.txt", &dir));
+            assert!(!is_fs_obj_marked("./test/a", &dir));
         }
 
         dirp_state.quit();
 
         Ok(())
     }
+
+    #[test]
+    fn test_hard_links() -> Result<(), DirpError> {
+        let dir_path = std::env::temp_dir().join("dirp_test_hard_links");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(dir_path.join("b"))?;
+        std::fs::write(dir_path.join("a"), vec![0u8; 1000])?;
+        std::fs::hard_link(dir_path.join("a"), dir_path.join("b/a"))?;
+        let a_path = dir_path.join("a");
+        let b_a_path = dir_path.join("b/a");
+
+        let dirp_state = DirpState::new(
+            dir_path.clone(),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+
+        // The inode is only counted once.
+        let mut dir_hash = DirHash::new();
+        let state_response = recv_complete_state(&dirp_state, &dir_path, &mut dir_hash);
+        assert_eq!(state_response.dirp_state.size_in_bytes, 1000);
+
+        // Removing one link frees nothing.
+        dirp_state.send(DirpStateMessage::MarkPath(a_path));
+        let state_response = recv_state(&dirp_state, &dir_path, &mut dir_hash);
+        assert_eq!(state_response.marked_summary.size_in_bytes, 1000);
+        assert_eq!(state_response.marked_summary.reclaimable_size_in_bytes, 0);
+
+        // Removing both frees the inode.
+        dirp_state.send(DirpStateMessage::MarkPath(b_a_path));
+        let state_response = recv_state(&dirp_state, &dir_path, &mut dir_hash);
+        assert_eq!(state_response.marked_summary.size_in_bytes, 2000);
+        assert_eq!(
+            state_response.marked_summary.reclaimable_size_in_bytes,
+            1000
+        );
+
+        // In a plan, a dir counts the links under it, however the scan shared them out.
+        dirp_state.send(DirpStateMessage::MarkPath(dir_path.join("b")));
+        recv_state(&dirp_state, &dir_path, &mut dir_hash);
+        let plan_entry_list = marked_plan_entry_list(&dir_path, SizeMode::Apparent, &dir_hash);
+        assert_eq!(
+            plan_entry_list
+                .iter()
+                .map(|plan_entry| (plan_entry.path.clone(), plan_entry.size_in_bytes))
+                .collect::<Vec<_>>(),
+            vec![(dir_path.join("a"), 1000), (dir_path.join("b"), 1000)],
+            "Error: Unexpected result."
+        );
+
+        dirp_state.quit();
+        std::fs::remove_dir_all(&dir_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_paths() -> Result<(), DirpError> {
+        let dir_path = std::env::temp_dir().join("dirp_test_delete_paths");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(dir_path.join("a/b"))?;
+        std::fs::create_dir_all(dir_path.join("c"))?;
+        std::fs::write(dir_path.join("a/b/1.txt"), "1")?;
+        std::fs::write(dir_path.join("c/2.txt"), "2")?;
+        std::os::unix::fs::symlink(dir_path.join("c"), dir_path.join("c.lnk"))?;
+
+        delete_paths(
+            &[dir_path.join("a"), dir_path.join("c.lnk")],
+            &ScanOptions::default(),
+        )?;
+
+        // The link goes, but not what it pointed to.
+        assert!(!dir_path.join("a").exists(), "Error: Unexpected result.");
+        assert!(
+            !dir_path.join("c.lnk").exists(),
+            "Error: Unexpected result."
+        );
+        assert!(
+            dir_path.join("c/2.txt").exists(),
+            "Error: Unexpected result."
+        );
+
+        std::fs::remove_dir_all(&dir_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_paths_left_out() -> Result<(), DirpError> {
+        let dir_path = std::env::temp_dir().join("dirp_test_delete_paths_left_out");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(dir_path.join("a/b"))?;
+        std::fs::create_dir_all(dir_path.join("c"))?;
+        std::fs::write(dir_path.join("a/1.txt"), "1")?;
+        std::fs::write(dir_path.join("a/b/keep.bin"), "2")?;
+        std::fs::write(dir_path.join("a/b/3.txt"), "3")?;
+        std::fs::write(dir_path.join("c/4.txt"), "4")?;
+
+        // An excluded file, never shown, is kept, along with the dirs it's in.
+        let scan_options = ScanOptions {
+            exclude_rules: ExcludeRules::new(&vec!["keep.bin".to_string()], false)
+                .expect("bad glob"),
+            ..Default::default()
+        };
+        let kept_path_list = delete_paths(&[dir_path.join("a")], &scan_options)?;
+        assert_eq!(
+            kept_path_list,
+            vec![dir_path.join("a/b/keep.bin")],
+            "Error: Unexpected result."
+        );
+        assert!(
+            dir_path.join("a/b/keep.bin").exists(),
+            "Error: Unexpected result."
+        );
+        assert!(
+            !dir_path.join("a/1.txt").exists(),
+            "Error: Unexpected result."
+        );
+        assert!(
+            !dir_path.join("a/b/3.txt").exists(),
+            "Error: Unexpected result."
+        );
+
+        // With '-x', nothing on another file system than the path being deleted is.
+        // Here 'c' is made out to be on another one.
+        let scan_options = ScanOptions {
+            root_dev: Some(0),
+            ..Default::default()
+        };
+        let c_dev = std::fs::symlink_metadata(dir_path.join("c"))?.st_dev();
+        let mut kept_path_list = Vec::new();
+        delete_path(
+            &dir_path.join("c"),
+            c_dev + 1,
+            &scan_options,
+            &mut kept_path_list,
+        )?;
+        assert_eq!(
+            kept_path_list,
+            vec![dir_path.join("c")],
+            "Error: Unexpected result."
+        );
+        assert!(
+            dir_path.join("c/4.txt").exists(),
+            "Error: Unexpected result."
+        );
+
+        std::fs::remove_dir_all(&dir_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rescan() -> Result<(), DirpError> {
+        let dir_path = std::env::temp_dir().join("dirp_test_rescan");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(dir_path.join("b/c"))?;
+        std::fs::write(dir_path.join("a"), vec![0u8; 1000])?;
+        std::fs::write(dir_path.join("b/c/d"), vec![0u8; 100])?;
+        let b_path = dir_path.join("b");
+        let d_path = dir_path.join("b/c/d");
+
+        let dirp_state = DirpState::new(
+            dir_path.clone(),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        assert_eq!(dirp_state.full_state()?.dirp_state.size_in_bytes, 1100);
+
+        std::fs::write(dir_path.join("b/c/e"), vec![0u8; 10])?;
+        dirp_state.send(DirpStateMessage::MarkPath(d_path.clone()));
+        dirp_state.send(DirpStateMessage::Rescan(b_path));
+
+        // The new file is counted, once, and the mark survives the rescan.
+        let state_response = dirp_state.full_state()?;
+        dirp_state.quit();
+        std::fs::remove_dir_all(&dir_path)?;
+
+        assert_eq!(state_response.dirp_state.size_in_bytes, 1110);
+        assert!(is_fs_obj_marked(&d_path, &state_response.dirp_state));
+
+        Ok(())
+    }
+
+    fn make_test_tree(dir_path: &Path, depth: u32) -> Result<(), DirpError> {
+        // Three sub dirs and four files a level, each file a different size.
+        std::fs::create_dir_all(dir_path)?;
+        for i in 0..4 {
+            let size = (depth * 1000 + i * 100 + dir_path.as_os_str().len() as u32) as usize;
+            std::fs::write(dir_path.join(format!("{}.txt", i)), vec![0u8; size])?;
+        }
+        if depth > 0 {
+            for i in 0..3 {
+                make_test_tree(&dir_path.join(format!("d{}", i)), depth - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_sizes(
+        dir_path: &Path,
+        size_hash: &mut HashMap<PathBuf, u64>,
+    ) -> Result<u64, DirpError> {
+        // The size of every dir under 'dir_path', one at a time, the way 'du' would.
+        let mut size_in_bytes = 0;
+        for dir_entry in std::fs::read_dir(dir_path)? {
+            let path = dir_entry?.path();
+            let meta_data = std::fs::symlink_metadata(&path)?;
+            if meta_data.is_dir() {
+                size_in_bytes += walk_sizes(&path, size_hash)?;
+            } else if meta_data.is_file() {
+                size_in_bytes += meta_data.len();
+            }
+        }
+        size_hash.insert(dir_path.to_path_buf(), size_in_bytes);
+        Ok(size_in_bytes)
+    }
+
+    fn collect_sizes(dir: &Dir, size_hash: &mut HashMap<PathBuf, u64>, is_all_marked: &mut bool) {
+        size_hash.insert(dir.path.clone(), dir.size_in_bytes);
+        *is_all_marked &= dir.is_marked;
+        for fs_obj in &dir.dir_obj_list {
+            match fs_obj {
+                FSObj::Dir(sub_dir) => collect_sizes(sub_dir, size_hash, is_all_marked),
+                FSObj::File(file) => *is_all_marked &= file.is_marked,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_order_stress() -> Result<(), DirpError> {
+        // However the scans of a deep tree finish, and whatever is rescanned or marked
+        // while they do, every dir ends up the size a sequential walk says it is.
+        let dir_path = std::env::temp_dir().join("dirp_test_scan_order_stress");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        make_test_tree(&dir_path, 5)?;
+        let mut expected_size_hash = HashMap::new();
+        walk_sizes(&dir_path, &mut expected_size_hash)?;
+        let rescan_path_list = [
+            dir_path.join("d0"),
+            dir_path.join("d1/d2"),
+            dir_path.join("d2/d0/d1"),
+            dir_path.join("d2/d0/d1/d1"),
+            dir_path.clone(),
+        ];
+
+        for round in 0..4 {
+            let dirp_state = DirpState::new(
+                dir_path.clone(),
+                SizeMode::Apparent,
+                ScanSource::Disk,
+                ScanOptions::default(),
+            );
+            // Keep rescanning and marking while the scan runs, so they land between
+            // scans finishing in all sorts of orders.
+            for i in 0..100 {
+                let rescan_path = &rescan_path_list[(i + round) % rescan_path_list.len()];
+                dirp_state.send(DirpStateMessage::Rescan(rescan_path.clone()));
+                dirp_state.send(DirpStateMessage::MarkPath(dir_path.clone()));
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let state_response = dirp_state.full_state()?;
+            dirp_state.quit();
+
+            let mut size_hash = HashMap::new();
+            let mut is_all_marked = true;
+            collect_sizes(
+                &state_response.dirp_state,
+                &mut size_hash,
+                &mut is_all_marked,
+            );
+            assert_eq!(size_hash, expected_size_hash, "Error: Unexpected result.");
+            assert!(is_all_marked, "Error: Unexpected result.");
+        }
+
+        std::fs::remove_dir_all(&dir_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watched_changes() -> Result<(), DirpError> {
+        // Changes made on disk while the tree is open are picked up by the watcher, and
+        // every dir above them resized.
+        let dir_path = std::env::temp_dir().join("dirp_test_watched_changes");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(dir_path.join("a/b/c"))?;
+        std::fs::create_dir_all(dir_path.join("a/d"))?;
+        std::fs::create_dir_all(dir_path.join("e"))?;
+        std::fs::write(dir_path.join("a/1.txt"), vec![0u8; 1000])?;
+        std::fs::write(dir_path.join("a/b/c/2.txt"), vec![0u8; 100])?;
+        std::fs::write(dir_path.join("a/d/3.txt"), vec![0u8; 10])?;
+        std::fs::write(dir_path.join("e/4.txt"), vec![0u8; 1])?;
+        let marked_path = dir_path.join("a/b/c/2.txt");
+
+        let dirp_state = DirpState::new(
+            dir_path.clone(),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        dirp_state.full_state()?;
+        dirp_state.send(DirpStateMessage::MarkPath(marked_path.clone()));
+
+        // Create, remove, and move a file and a dir, all in 'a'.
+        std::fs::write(dir_path.join("a/5.txt"), vec![0u8; 20000])?;
+        std::fs::remove_file(dir_path.join("a/1.txt"))?;
+        std::fs::rename(dir_path.join("a/d"), dir_path.join("e/d"))?;
+        std::fs::write(dir_path.join("a/b/c/6.txt"), vec![0u8; 300000])?;
+        let mut expected_size_hash = HashMap::new();
+        walk_sizes(&dir_path, &mut expected_size_hash)?;
+
+        // The watcher is asynchronous, so wait for it to catch up.
+        let mut size_hash = HashMap::new();
+        let mut dir = None;
+        for _ in 0..100 {
+            let state_dir = dirp_state.full_state()?.dirp_state;
+            size_hash.clear();
+            collect_sizes(&state_dir, &mut size_hash, &mut true);
+            dir = Some(state_dir);
+            if size_hash == expected_size_hash {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        dirp_state.quit();
+        std::fs::remove_dir_all(&dir_path)?;
+
+        assert_eq!(size_hash, expected_size_hash, "Error: Unexpected result.");
+        // Kept through the rescan of 'a'.
+        assert!(is_fs_obj_marked(&marked_path, &dir.unwrap()));
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_non_utf8_path() -> Result<(), DirpError> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        // Linux allows any bytes but '/' and NUL in a name. Others don't.
+        let dir_path = std::env::temp_dir().join("dirp_test_non_utf8_path");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path)?;
+        let bad_path = dir_path.join(OsStr::from_bytes(b"bad-\xff.txt"));
+        std::fs::write(&bad_path, vec![0u8; 100])?;
+
+        let dirp_state = DirpState::new(
+            dir_path.clone(),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        dirp_state.full_state()?;
+        dirp_state.send(DirpStateMessage::MarkPath(bad_path.clone()));
+        let state_response = dirp_state.full_state()?;
+        dirp_state.quit();
+
+        // The scanned path is the exact one on disk, so it can be removed.
+        let scanned_path = match find_fs_obj(&bad_path, &state_response.dirp_state) {
+            Some(FSObj::File(file)) => file.path.clone(),
+            _ => panic!("{} not found.", bad_path.display()),
+        };
+        assert!(is_fs_obj_marked(&bad_path, &state_response.dirp_state));
+        delete_paths(&[scanned_path], &ScanOptions::default())?;
+        let is_removed = !bad_path.exists();
+        std::fs::remove_dir_all(&dir_path)?;
+
+        assert!(is_removed, "Error: Unexpected result.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<(), DirpError> {
+        // Scan ./test, then browse that tree as a saved scan.
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let root_dir = dirp_state.full_state()?.dirp_state;
+        dirp_state.quit();
+
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Snapshot(root_dir),
+            ScanOptions::default(),
+        );
+
+        let mut dir_hash = DirHash::new();
+        let state_response = recv_complete_state(&dirp_state, Path::new("./test"), &mut dir_hash);
+        assert_eq!(state_response.dirp_state.size_in_bytes, 8080);
+        assert_eq!(state_response.dirp_state.dir_obj_list.len(), 5);
+        assert_eq!(state_response.scan_progress.dirs_scanned, 6);
+        assert_eq!(state_response.scan_progress.files_scanned, 8);
+
+        // Sub dirs open, and mark, as they do after a scan.
+        dirp_state.send(DirpStateMessage::OpenDir(PathBuf::from("./test/c")));
+        dirp_state.send(DirpStateMessage::MarkPath(PathBuf::from("./test/c")));
+        let dir = dirp_state.full_state()?.dirp_state;
+        assert!(is_fs_obj_marked("./test/c/d/5.txt", &dir));
+        match find_fs_obj("./test/c", &dir) {
+            Some(FSObj::Dir(c)) => assert_eq!(c.size_in_bytes, 2020),
+            _ => panic!("./test/c not found."),
+        }
+
+        dirp_state.quit();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_change_list() -> Result<(), DirpError> {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let dir = dirp_state.full_state()?.dirp_state;
+        dirp_state.quit();
+        let mut dir_hash = DirHash::new();
+        load_snapshot_dir(dir, &mut dir_hash, &mut ScanProgress::default());
+        let mut ui_dir_hash = DirHash::new();
+        for dir_change in dir_hash.take_dir_change_list() {
+            assert!(
+                matches!(dir_change, DirChange::Listed(_)),
+                "Error: Unexpected result."
+            );
+            ui_dir_hash.apply(dir_change);
+        }
+
+        // A mark goes with just the entry it's on, and a resize without any.
+        do_mark_deep(
+            Path::new("./test/c/4.txt"),
+            true,
+            &mut dir_hash,
+            &mut HashMap::new(),
+        );
+        resize_parent_dirs(Path::new("./test/c/d"), 10, 0, &mut dir_hash);
+        let mut dir_change_list = dir_hash.take_dir_change_list();
+        dir_change_list.sort_by_key(|dir_change| match dir_change {
+            DirChange::Updated(dir_update) => dir_update.path.clone(),
+            _ => panic!("Error: Unexpected result."),
+        });
+        match &dir_change_list[..] {
+            [DirChange::Updated(root_update), DirChange::Updated(c_update)] => {
+                assert_eq!(
+                    root_update.path,
+                    PathBuf::from("./test"),
+                    "Error: Unexpected result."
+                );
+                assert!(
+                    root_update.entry_list.is_empty(),
+                    "Error: Unexpected result."
+                );
+                assert_eq!(
+                    c_update.size_in_bytes,
+                    dir_hash.get(Path::new("./test/c")).unwrap().size_in_bytes,
+                    "Error: Unexpected result."
+                );
+                assert_eq!(c_update.entry_list.len(), 1, "Error: Unexpected result.");
+            }
+            _ => panic!("Error: Unexpected result."),
+        }
+
+        for dir_change in dir_change_list {
+            ui_dir_hash.apply(dir_change);
+        }
+        let ui_dir = build_result_tree(Path::new("./test"), true, SizeMode::Apparent, &ui_dir_hash);
+        let dir = build_result_tree(Path::new("./test"), true, SizeMode::Apparent, &dir_hash);
+        assert_eq!(
+            ui_dir.size_in_bytes, dir.size_in_bytes,
+            "Error: Unexpected result."
+        );
+        assert!(
+            is_fs_obj_marked("./test/c/4.txt", &ui_dir),
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_path_list() -> Result<(), DirpError> {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let dir = dirp_state.full_state()?.dirp_state;
+        dirp_state.quit();
+        let mut dir_hash = DirHash::new();
+        load_snapshot_dir(dir, &mut dir_hash, &mut ScanProgress::default());
+
+        // Only 5.txt, and the dirs that lead to it, are left.
+        let root_path = Path::new("./test");
+        let matcher = SkimMatcherV2::default();
+        let mut filter = Filter::new("5txt".to_string(), root_path, &matcher, &dir_hash);
+        let mut path_list = filter.shown_path_list();
+        path_list.sort();
+        assert_eq!(
+            path_list,
+            vec![
+                PathBuf::from("./test"),
+                PathBuf::from("./test/c"),
+                PathBuf::from("./test/c/d"),
+                PathBuf::from("./test/c/d/5.txt")
+            ],
+            "Error: Unexpected result."
+        );
+
+        // Kept up to date as dirs go, and come back.
+        let d_dir = dir_hash.get(Path::new("./test/c/d")).unwrap().clone();
+        filter.remove_dir(root_path, &d_dir.path);
+        assert_eq!(
+            filter.shown_path_list(),
+            vec![PathBuf::from("./test")],
+            "Error: Unexpected result."
+        );
+        match filter.take_filter_change() {
+            Some(FilterChange::Changed {
+                shown_path_list,
+                mut hidden_path_list,
+            }) => {
+                hidden_path_list.sort();
+                assert!(shown_path_list.is_empty(), "Error: Unexpected result.");
+                assert_eq!(
+                    hidden_path_list,
+                    vec![
+                        PathBuf::from("./test/c"),
+                        PathBuf::from("./test/c/d"),
+                        PathBuf::from("./test/c/d/5.txt")
+                    ],
+                    "Error: Unexpected result."
+                );
+            }
+            _ => panic!("Error: Unexpected result."),
+        }
+        filter.list_dir(root_path, &d_dir, &matcher);
+        assert_eq!(
+            filter.shown_path_list().len(),
+            4,
+            "Error: Unexpected result."
+        );
+
+        let filter = Filter::new(
+            "nothing_like_it".to_string(),
+            root_path,
+            &matcher,
+            &dir_hash,
+        );
+        assert_eq!(
+            filter.shown_path_list(),
+            vec![PathBuf::from("./test")],
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,212 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// Always respected, so dirp can be told to leave things alone.
+const DIRP_IGNORE_FILE_NAME: &str = ".dirpignore";
+// Only respected when 'use_ignore_files' is set.
+const IGNORE_FILE_NAME_LIST: [&str; 2] = [".gitignore", ".ignore"];
+
+//
+// Decides which paths the scan leaves out. Rules come from '--exclude' globs, the
+// 'exclude' lines of the user's config file, .dirpignore files, and optionally
+// .gitignore and .ignore files.
+//
+// A glob is matched against both the whole path, and the file name, so 'node_modules'
+// and '*.o' match anywhere in the tree. Ignore files follow the usual gitignore rules,
+// with the file nearest to a path taking precedence.
+//
+#[derive(Debug)]
+pub struct ExcludeRules {
+    glob_set: GlobSet,
+    use_ignore_files: bool,
+    // Dir path -> the ignore files in that dir.
+    ignore_file_cache: Mutex<HashMap<PathBuf, IgnoreFiles>>,
+}
+
+#[derive(Debug, Clone)]
+struct IgnoreFiles {
+    // None if there are none.
+    rules: Option<Arc<Gitignore>>,
+    // The path, mtime and size of each, as read. Changes if any of them do.
+    stamp: String,
+}
+
+impl Default for ExcludeRules {
+    fn default() -> Self {
+        ExcludeRules {
+            glob_set: GlobSet::empty(),
+            use_ignore_files: false,
+            ignore_file_cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ExcludeRules {
+    pub fn new(glob_list: &Vec<String>, use_ignore_files: bool) -> Result<Self, globset::Error> {
+        let mut glob_set_builder = GlobSetBuilder::new();
+        for glob in glob_list {
+            glob_set_builder.add(Glob::new(glob)?);
+        }
+
+        Ok(ExcludeRules {
+            glob_set: glob_set_builder.build()?,
+            use_ignore_files,
+            ignore_file_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    //
+    // The ignore file rules that apply to entries of 'dir_path', nearest dir first.
+    // Fetch these once per dir, and pass them to is_excluded() for each entry.
+    //
+    pub fn ignore_file_chain(&self, dir_path: &Path) -> Vec<Arc<Gitignore>> {
+        let mut ignore_file_chain = Vec::new();
+
+        let mut dir_path_opt = Some(dir_path);
+        while let Some(dir_path) = dir_path_opt {
+            if dir_path.as_os_str().is_empty() {
+                // The parent of a relative path's first component.
+                break;
+            }
+            if let Some(gitignore) = self.ignore_files(dir_path).rules {
+                ignore_file_chain.push(gitignore);
+            }
+            dir_path_opt = dir_path.parent();
+        }
+
+        ignore_file_chain
+    }
+
+    pub fn is_excluded(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        ignore_file_chain: &Vec<Arc<Gitignore>>,
+    ) -> bool {
+        if self.glob_set.is_match(path) {
+            return true;
+        }
+        if let Some(file_name) = path.file_name() {
+            if self.glob_set.is_match(file_name) {
+                return true;
+            }
+        }
+
+        for gitignore in ignore_file_chain {
+            let is_match = gitignore.matched(path, is_dir);
+            if is_match.is_ignore() {
+                return true;
+            } else if is_match.is_whitelist() {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    //
+    // Stands for the ignore files that ignore_file_chain() reads for 'dir_path', as they
+    // were read. It changes when any of them is added, removed or edited.
+    //
+    pub fn ignore_file_key(&self, dir_path: &Path) -> String {
+        let mut ignore_file_key = String::new();
+
+        let mut dir_path_opt = Some(dir_path);
+        while let Some(dir_path) = dir_path_opt {
+            if dir_path.as_os_str().is_empty() {
+                break;
+            }
+            ignore_file_key.push_str(&self.ignore_files(dir_path).stamp);
+            dir_path_opt = dir_path.parent();
+        }
+
+        ignore_file_key
+    }
+
+    fn ignore_files(&self, dir_path: &Path) -> IgnoreFiles {
+        let mut ignore_file_cache = self
+            .ignore_file_cache
+            .lock()
+            .expect("ignore_file_cache lock poisoned");
+
+        ignore_file_cache
+            .entry(dir_path.to_path_buf())
+            .or_insert_with(|| {
+                let mut ignore_file_path_list = Vec::new();
+                if self.use_ignore_files {
+                    for file_name in IGNORE_FILE_NAME_LIST {
+                        ignore_file_path_list.push(dir_path.join(file_name));
+                    }
+                }
+                ignore_file_path_list.push(dir_path.join(DIRP_IGNORE_FILE_NAME));
+
+                let mut gitignore_builder = GitignoreBuilder::new(dir_path);
+                let mut stamp = String::new();
+                for ignore_file_path in ignore_file_path_list {
+                    let meta_data = match fs::metadata(&ignore_file_path) {
+                        Ok(meta_data) if meta_data.is_file() => meta_data,
+                        _ => continue,
+                    };
+                    stamp.push_str(&format!(
+                        "{}:{:?}:{};",
+                        ignore_file_path.display(),
+                        meta_data.modified().ok(),
+                        meta_data.len()
+                    ));
+                    // A bad line only loses that line's rule.
+                    let _ = gitignore_builder.add(ignore_file_path);
+                }
+                if stamp.is_empty() {
+                    return IgnoreFiles { rules: None, stamp };
+                }
+
+                IgnoreFiles {
+                    rules: gitignore_builder.build().ok().map(Arc::new),
+                    stamp,
+                }
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_exclude_rules() -> Result<(), std::io::Error> {
+        let dir_path = std::env::temp_dir().join("dirp_test_exclude_rules");
+        let _ = fs::remove_dir_all(&dir_path);
+        fs::create_dir_all(dir_path.join("sub"))?;
+        fs::write(dir_path.join(".gitignore"), "*.log\n")?;
+        fs::write(dir_path.join("sub/.dirpignore"), "keep.bin\n!keep.log\n")?;
+
+        let exclude_rules =
+            ExcludeRules::new(&vec!["node_modules".to_string()], true).expect("bad glob");
+        let chain = exclude_rules.ignore_file_chain(&dir_path.join("sub"));
+
+        assert!(exclude_rules.is_excluded(&dir_path.join("sub/node_modules"), true, &chain));
+        assert!(exclude_rules.is_excluded(&dir_path.join("sub/a.log"), false, &chain));
+        assert!(exclude_rules.is_excluded(&dir_path.join("sub/keep.bin"), false, &chain));
+        assert!(!exclude_rules.is_excluded(&dir_path.join("sub/keep.log"), false, &chain));
+        assert!(!exclude_rules.is_excluded(&dir_path.join("sub/a.txt"), false, &chain));
+
+        // Without 'use_ignore_files' only the .dirpignore applies.
+        let exclude_rules = ExcludeRules::new(&vec![], false).expect("bad glob");
+        let chain = exclude_rules.ignore_file_chain(&dir_path.join("sub"));
+
+        assert!(!exclude_rules.is_excluded(&dir_path.join("sub/a.log"), false, &chain));
+        assert!(exclude_rules.is_excluded(&dir_path.join("sub/keep.bin"), false, &chain));
+
+        fs::remove_dir_all(&dir_path)?;
+
+        Ok(())
+    }
+}
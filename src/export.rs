@@ -0,0 +1,449 @@
+use crate::ncdu::{read_ncdu_export, write_ncdu_export};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+//
+// dirp's JSON export format. The schema is these structs, not the in memory model,
+// so the model can change without breaking anyone reading exports. Any change to
+// what is written here needs EXPORT_VERSION bumped. For example:
+//
+//     {
+//       "format": "dirp",
+//       "version": 2,
+//       "size_mode": "apparent",
+//       "root": {
+//         "path": "/home/me/test",
+//         "kind": "dir",
+//         "size": 8080,
+//         "disk_size": 32768,
+//         "percent": 100,
+//         "marked": false,
+//         "excluded": false,
+//         "mtime": 1676160000,
+//         "errors": [ { "path": "/home/me/test/private", "message": "..." } ],
+//         "children": [ ... ]
+//       }
+//     }
+//
+// 'size' is the apparent size, and 'disk_size' the disk usage. A dir's sizes are the
+// totals for everything under it. 'percent' is of the root's size, in 'size_mode'.
+// 'inode' is only present on hard linked files, and 'errors' (what couldn't be read
+// while listing a dir, added in version 2) only on dirs that had some. JSON strings are
+// UTF-8, so a path that isn't is written lossily (bad bytes as U+FFFD) and won't import
+// back exactly.
+//
+pub const EXPORT_FORMAT: &str = "dirp";
+pub const EXPORT_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Export {
+    pub format: String,
+    pub version: u32,
+    pub size_mode: ExportSizeMode,
+    pub root: ExportEntry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportSizeMode {
+    Apparent,
+    Disk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportKind {
+    File,
+    Symlink,
+    Dir,
+    MountPoint,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportEntry {
+    pub path: String,
+    pub kind: ExportKind,
+    pub size: u64,
+    pub disk_size: u64,
+    pub percent: u8,
+    pub marked: bool,
+    pub excluded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<ExportInode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ExportError>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ExportEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportError {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportInode {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+}
+
+//
+// Waits for the scan to complete, writes the tree to 'export_path', and exits.
+//
+pub fn export_runloop(
+    args: Args,
+    export_path: &Path,
+    export_format: ExportFormat,
+) -> Result<(), DirpError> {
+    let dirp_state = DirpState::new(
+        args.path.clone(),
+        args.size_mode,
+        args.scan_source,
+        args.scan_options,
+    );
+
+    let state_response = dirp_state.full_state()?;
+    dirp_state.quit();
+
+    match export_format {
+        ExportFormat::Json => write_json_export(
+            &state_response.dirp_state,
+            state_response.size_mode,
+            export_path,
+        ),
+        ExportFormat::Ncdu => write_ncdu_export(&state_response.dirp_state, export_path),
+    }
+}
+
+//
+// Reads a saved scan, in either format. dirp's exports are JSON objects, and ncdu's
+// dumps are JSON arrays.
+//
+pub fn read_scan_file(file_path: &Path) -> Result<Dir, DirpError> {
+    let mut reader = BufReader::new(fs::File::open(file_path)?);
+    let first_char = loop {
+        let buffer = reader.fill_buf()?;
+        match buffer.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            Some(index) => break buffer[index],
+            None if buffer.is_empty() => {
+                return Err(DirpError::BadScanFile("The file is empty.".to_string()))
+            }
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
+            }
+        }
+    };
+
+    match first_char {
+        b'[' => read_ncdu_export(file_path),
+        _ => read_json_export(file_path),
+    }
+}
+
+//
+// 'dir' is a full tree, as built by build_result_tree(.., include_all=true, ..).
+//
+pub fn write_json_export(dir: &Dir, size_mode: SizeMode, path: &Path) -> Result<(), DirpError> {
+    let export = Export {
+        format: EXPORT_FORMAT.to_string(),
+        version: EXPORT_VERSION,
+        size_mode: match size_mode {
+            SizeMode::Apparent => ExportSizeMode::Apparent,
+            SizeMode::Disk => ExportSizeMode::Disk,
+        },
+        root: export_dir_entry(dir),
+    };
+
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(&mut writer, &export)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn export_dir_entry(dir: &Dir) -> ExportEntry {
+    ExportEntry {
+        path: dir.path.to_string_lossy().to_string(),
+        kind: ExportKind::Dir,
+        size: dir.size_in_bytes,
+        disk_size: dir.disk_size_in_bytes,
+        percent: dir.percent,
+        marked: dir.is_marked,
+        excluded: dir.is_excluded,
+        mtime: Some(dir.mtime),
+        inode: None,
+        errors: dir
+            .error_list
+            .iter()
+            .map(|scan_error| ExportError {
+                path: scan_error.path.to_string_lossy().to_string(),
+                message: scan_error.message.clone(),
+            })
+            .collect(),
+        children: dir.dir_obj_list.iter().map(export_entry).collect(),
+    }
+}
+
+fn export_entry(fs_obj: &FSObj) -> ExportEntry {
+    match fs_obj {
+        FSObj::Dir(dir) => export_dir_entry(dir),
+        FSObj::DirRef(_) => {
+            panic!("Invalid state.");
+        }
+        FSObj::File(file) => ExportEntry {
+            path: file.path.to_string_lossy().to_string(),
+            kind: ExportKind::File,
+            size: file.size_in_bytes,
+            disk_size: file.disk_size_in_bytes,
+            percent: file.percent,
+            marked: file.is_marked,
+            excluded: file.is_excluded,
+            mtime: Some(file.mtime),
+            inode: match file.nlink > 1 {
+                true => Some(ExportInode {
+                    dev: file.dev,
+                    ino: file.ino,
+                    nlink: file.nlink,
+                }),
+                false => None,
+            },
+            errors: Vec::new(),
+            children: Vec::new(),
+        },
+        FSObj::SymLink(sym_link) => ExportEntry {
+            path: sym_link.path.to_string_lossy().to_string(),
+            kind: ExportKind::Symlink,
+            size: sym_link.size_in_bytes,
+            disk_size: sym_link.disk_size_in_bytes,
+            percent: sym_link.percent,
+            marked: sym_link.is_marked,
+            excluded: sym_link.is_excluded,
+            mtime: Some(sym_link.mtime),
+            inode: None,
+            errors: Vec::new(),
+            children: Vec::new(),
+        },
+        FSObj::MountPoint(mount_point) => ExportEntry {
+            path: mount_point.path.to_string_lossy().to_string(),
+            kind: ExportKind::MountPoint,
+            size: 0,
+            disk_size: 0,
+            percent: 0,
+            marked: false,
+            excluded: false,
+            mtime: None,
+            inode: None,
+            errors: Vec::new(),
+            children: Vec::new(),
+        },
+    }
+}
+
+//
+// Reads a file written by write_json_export(), back into a full tree.
+//
+pub fn read_json_export(file_path: &Path) -> Result<Dir, DirpError> {
+    let reader = BufReader::new(fs::File::open(file_path)?);
+    let export: Export = serde_json::from_reader(reader)?;
+    if export.format != EXPORT_FORMAT {
+        return Err(DirpError::BadScanFile(format!(
+            "'{}' is not a dirp export.",
+            export.format
+        )));
+    }
+    if export.version > EXPORT_VERSION {
+        return Err(DirpError::BadScanFile(format!(
+            "Export version {} is newer than this dirp reads ({}).",
+            export.version, EXPORT_VERSION
+        )));
+    }
+
+    match import_entry(export.root) {
+        FSObj::Dir(mut root_dir) => {
+            root_dir.is_open = true;
+            Ok(root_dir)
+        }
+        _ => Err(DirpError::BadScanFile(
+            "The root of the export is not a dir.".to_string(),
+        )),
+    }
+}
+
+fn import_entry(entry: ExportEntry) -> FSObj {
+    let mtime = entry.mtime.unwrap_or(0);
+    match entry.kind {
+        ExportKind::Dir => FSObj::Dir(Dir {
+            path: PathBuf::from(entry.path),
+            size_in_bytes: entry.size,
+            disk_size_in_bytes: entry.disk_size,
+            percent: entry.percent,
+            is_marked: entry.marked,
+            is_excluded: entry.excluded,
+            mtime,
+            is_open: false,
+            dir_obj_list: entry.children.into_iter().map(import_entry).collect(),
+            error_list: entry
+                .errors
+                .into_iter()
+                .map(|error| ScanError {
+                    path: PathBuf::from(error.path),
+                    message: error.message,
+                })
+                .collect(),
+        }),
+        ExportKind::File => {
+            let (dev, ino, nlink) = match entry.inode {
+                Some(inode) => (inode.dev, inode.ino, inode.nlink),
+                None => (0, 0, 1),
+            };
+            FSObj::File(File {
+                path: PathBuf::from(entry.path),
+                size_in_bytes: entry.size,
+                disk_size_in_bytes: entry.disk_size,
+                percent: entry.percent,
+                is_marked: entry.marked,
+                is_excluded: entry.excluded,
+                mtime,
+                dev,
+                ino,
+                nlink,
+            })
+        }
+        ExportKind::Symlink => FSObj::SymLink(SymLink {
+            path: PathBuf::from(entry.path),
+            size_in_bytes: entry.size,
+            disk_size_in_bytes: entry.disk_size,
+            percent: entry.percent,
+            is_marked: entry.marked,
+            is_excluded: entry.excluded,
+            mtime,
+        }),
+        ExportKind::MountPoint => FSObj::MountPoint(MountPoint {
+            path: PathBuf::from(entry.path),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_json_export() -> Result<(), DirpError> {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let state_response = dirp_state.full_state()?;
+        dirp_state.quit();
+
+        let export_path = std::env::temp_dir().join("dirp_test_write_json_export.json");
+        write_json_export(&state_response.dirp_state, SizeMode::Apparent, &export_path)?;
+
+        let export: Export = serde_json::from_str(&fs::read_to_string(&export_path)?)?;
+        let root_dir = read_json_export(&export_path)?;
+        fs::remove_file(&export_path)?;
+
+        assert_eq!(export.format, "dirp", "Error: Unexpected result.");
+        assert_eq!(export.version, EXPORT_VERSION, "Error: Unexpected result.");
+        assert_eq!(export.root.path, "./test", "Error: Unexpected result.");
+        assert_eq!(
+            export.root.kind,
+            ExportKind::Dir,
+            "Error: Unexpected result."
+        );
+        assert_eq!(export.root.size, 8080, "Error: Unexpected result.");
+        assert_eq!(export.root.percent, 100, "Error: Unexpected result.");
+
+        let d = export
+            .root
+            .children
+            .iter()
+            .find(|entry| entry.path == "./test/c")
+            .and_then(|c| c.children.iter().find(|entry| entry.path == "./test/c/d"))
+            .expect("./test/c/d not exported.");
+        assert_eq!(d.kind, ExportKind::Dir, "Error: Unexpected result.");
+        assert_eq!(d.size, 1010, "Error: Unexpected result.");
+        assert_eq!(d.children.len(), 1, "Error: Unexpected result.");
+        assert_eq!(d.children[0].kind, ExportKind::File);
+        assert!(d.children[0].mtime.is_some(), "Error: Unexpected result.");
+
+        // Reading it back gives the tree that was written.
+        assert_eq!(
+            root_dir.path,
+            Path::new("./test"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(root_dir.size_in_bytes, 8080, "Error: Unexpected result.");
+        assert_eq!(
+            root_dir.dir_obj_list.len(),
+            state_response.dirp_state.dir_obj_list.len(),
+            "Error: Unexpected result."
+        );
+        assert!(root_dir.is_open, "Error: Unexpected result.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_export_errors() -> Result<(), DirpError> {
+        let scan_error = |path: &str, message: &str| ScanError {
+            path: PathBuf::from(path),
+            message: message.to_string(),
+        };
+        let sub_dir = Dir {
+            path: PathBuf::from("/r/d"),
+            size_in_bytes: 0,
+            disk_size_in_bytes: 0,
+            percent: 0,
+            is_marked: false,
+            is_excluded: false,
+            mtime: 0,
+            is_open: false,
+            dir_obj_list: Vec::new(),
+            error_list: vec![scan_error("/r/d", "Permission denied (os error 13)")],
+        };
+        let root_dir = Dir {
+            path: PathBuf::from("/r"),
+            dir_obj_list: vec![FSObj::Dir(sub_dir.clone())],
+            error_list: vec![
+                scan_error("/r/a", "No such file or directory (os error 2)"),
+                scan_error("/r/b", "Permission denied (os error 13)"),
+            ],
+            ..sub_dir
+        };
+
+        let export_path = std::env::temp_dir().join("dirp_test_json_export_errors.json");
+        write_json_export(&root_dir, SizeMode::Apparent, &export_path)?;
+        let read_root_dir = read_json_export(&export_path)?;
+        fs::remove_file(&export_path)?;
+
+        assert_eq!(
+            read_root_dir.error_list, root_dir.error_list,
+            "Error: Unexpected result."
+        );
+        match &read_root_dir.dir_obj_list[..] {
+            [FSObj::Dir(read_sub_dir)] => assert_eq!(
+                read_sub_dir.error_list,
+                vec![scan_error("/r/d", "Permission denied (os error 13)")],
+                "Error: Unexpected result."
+            ),
+            _ => panic!("Error: Unexpected result."),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::types::*;
+use log::{debug, warn};
+use notify::{recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{path::Path, sync::mpsc::Sender};
+
+//
+// Watches the dirs a scan has read, and forwards any changes in them to the state thread
+// as PathCreated, PathRemoved and PathModified messages. Each dir is watched on its own,
+// as it's taken in (see watch_dir()), so only what was scanned is watched: nothing the
+// exclude rules leave out, and, with '-x', nothing past a mount point. The watch is
+// torn down when the watcher is dropped.
+//
+pub struct FsWatcher {
+    watcher: RecommendedWatcher,
+    is_watch_failed: bool,
+}
+
+impl FsWatcher {
+    pub fn new(dirp_state_sender: Sender<DirpStateMessage>) -> Result<FsWatcher, DirpError> {
+        let watcher = recommended_watcher(move |result: notify::Result<Event>| {
+            if let Ok(event) = result {
+                for message in event_to_messages(event) {
+                    // A send error means the state thread has exited, so there is
+                    // nobody left to tell.
+                    let _ = dirp_state_sender.send(message);
+                }
+            }
+        })?;
+
+        Ok(FsWatcher {
+            watcher,
+            is_watch_failed: false,
+        })
+    }
+
+    //
+    // Watches 'dir_path', but not the dirs in it, which are watched as they are scanned.
+    // A dir that can't be watched (once the system's limit on watches is reached, say)
+    // just won't have its changes picked up. 'r' still works. Only the first failure is
+    // a warning, so a big tree doesn't fill the log with them.
+    //
+    pub fn watch_dir(&mut self, dir_path: &Path) {
+        if let Err(error) = self.watcher.watch(dir_path, RecursiveMode::NonRecursive) {
+            if self.is_watch_failed {
+                debug!("Can not watch {}: {:?}", dir_path.display(), error);
+            } else {
+                warn!("Can not watch {}: {:?}", dir_path.display(), error);
+                self.is_watch_failed = true;
+            }
+        }
+    }
+}
+
+fn event_to_messages(event: Event) -> Vec<DirpStateMessage> {
+    let mut messages = Vec::new();
+
+    for path in event.paths {
+        match event.kind {
+            EventKind::Create(_) => messages.push(DirpStateMessage::PathCreated(path)),
+            EventKind::Remove(_) => messages.push(DirpStateMessage::PathRemoved(path)),
+            EventKind::Modify(_) => messages.push(DirpStateMessage::PathModified(path)),
+            _ => { /* Access events don't change sizes. */ }
+        }
+    }
+
+    messages
+}
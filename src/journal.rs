@@ -0,0 +1,359 @@
+use crate::types::*;
+use chrono::{Local, TimeZone};
+use dialoguer::{console::Term, theme::ColorfulTheme, FuzzySelect};
+use home::home_dir;
+use log::info;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+
+//
+// A record of what dirp has moved to the Trash, one session (one 'x', or one 'apply')
+// at a time, so 'dirp undo' can put the last of them back. Kept in
+// $XDG_DATA_HOME/dirp/journal.json (~/.local/share/dirp/journal.json if XDG_DATA_HOME
+// isn't set). Permanent deletes aren't recorded, as there's nothing to put back.
+//
+// Putting things back relies on the trash crate's list and restore, so only works with
+// the freedesktop.org Trash (Linux). Paths are kept as the hex of their bytes, so one
+// that isn't UTF-8 is matched against the Trash exactly.
+//
+const MAX_SESSION_COUNT: usize = 20;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    // Oldest first.
+    session_list: Vec<JournalSession>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalSession {
+    time: i64,
+    #[serde(
+        rename = "hex_path_list",
+        serialize_with = "serialize_path_list",
+        deserialize_with = "deserialize_path_list"
+    )]
+    path_list: Vec<PathBuf>,
+}
+
+fn serialize_path_list<S: Serializer>(
+    path_list: &[PathBuf],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(path_list.iter().map(|path| {
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    }))
+}
+
+fn deserialize_path_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PathBuf>, D::Error> {
+    let mut path_list = Vec::new();
+    for hex_path in Vec::<String>::deserialize(deserializer)? {
+        let bytes = (0..hex_path.len())
+            .step_by(2)
+            .map(|index| {
+                hex_path
+                    .get(index..index + 2)
+                    .and_then(|hex_byte| u8::from_str_radix(hex_byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| de::Error::custom(format!("'{}' is not a hex path", hex_path)))?;
+        path_list.push(PathBuf::from(OsStr::from_bytes(&bytes)));
+    }
+
+    Ok(path_list)
+}
+
+fn journal_dir_path() -> Option<PathBuf> {
+    let data_dir_path = match env::var_os("XDG_DATA_HOME") {
+        Some(data_home) if !data_home.is_empty() => PathBuf::from(data_home),
+        _ => home_dir()?.join(".local").join("share"),
+    };
+    Some(data_dir_path.join("dirp"))
+}
+
+//
+// Records that the paths in 'path_list' were just moved to the Trash.
+//
+pub fn record_session(path_list: &[PathBuf]) -> Result<(), DirpError> {
+    match journal_dir_path() {
+        Some(journal_dir_path) => record_session_in(&journal_dir_path, path_list),
+        None => Ok(()),
+    }
+}
+
+fn record_session_in(journal_dir_path: &Path, path_list: &[PathBuf]) -> Result<(), DirpError> {
+    let journal_file_path = journal_dir_path.join("journal.json");
+    info!(
+        "Recording {} paths moved to the Trash in {}",
+        path_list.len(),
+        journal_file_path.to_string_lossy()
+    );
+    let mut journal = read_journal(&journal_file_path);
+    journal.session_list.push(JournalSession {
+        time: Local::now().timestamp(),
+        path_list: path_list.to_vec(),
+    });
+    if journal.session_list.len() > MAX_SESSION_COUNT {
+        let extra_count = journal.session_list.len() - MAX_SESSION_COUNT;
+        journal.session_list.drain(..extra_count);
+    }
+
+    write_journal(&journal, &journal_file_path)
+}
+
+fn read_journal(journal_file_path: &Path) -> Journal {
+    // A missing (or unreadable) journal is an empty one.
+    fs::read_to_string(journal_file_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn write_journal(journal: &Journal, journal_file_path: &Path) -> Result<(), DirpError> {
+    if let Some(journal_dir_path) = journal_file_path.parent() {
+        fs::create_dir_all(journal_dir_path)?;
+    }
+    let mut writer = BufWriter::new(fs::File::create(journal_file_path)?);
+    serde_json::to_writer(&mut writer, journal)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+//
+// Puts back what the last session moved to the Trash, and drops it from the journal,
+// so the next undo goes back one more. Asks first, unless told not to.
+//
+pub fn undo_runloop(args: Args) -> Result<(), DirpError> {
+    match journal_dir_path() {
+        Some(journal_dir_path) => undo(&args, &journal_dir_path),
+        None => {
+            println!("There is no home directory, so no journal of what dirp removed.");
+            Ok(())
+        }
+    }
+}
+
+fn undo(args: &Args, journal_dir_path: &Path) -> Result<(), DirpError> {
+    let journal_file_path = journal_dir_path.join("journal.json");
+    let mut journal = read_journal(&journal_file_path);
+    let session = match journal.session_list.last() {
+        Some(session) => session.clone(),
+        None => {
+            println!("dirp hasn't moved anything to the Trash, so there is nothing to undo.");
+            return Ok(());
+        }
+    };
+
+    println!();
+    for path in &session.path_list {
+        println!("{}", path.to_string_lossy());
+    }
+    println!();
+    let time = Local
+        .timestamp_opt(session.time, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    if args.is_dry_run {
+        println!(
+            "These {} paths were moved to the Trash at {}. This is a dry run, so nothing was put back.",
+            session.path_list.len(),
+            time
+        );
+        return Ok(());
+    }
+
+    if !args.is_confirmed {
+        println!(
+            "Put these {} paths, moved to the Trash at {}, back?",
+            session.path_list.len(),
+            time
+        );
+        let items = vec!["No", "Yes"];
+        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+            .items(&items)
+            .default(0)
+            .interact_on_opt(&Term::stdout())?;
+        if selection != Some(1) {
+            return Ok(());
+        }
+    }
+
+    let restored_count = restore_session(&session)?;
+    journal.session_list.pop();
+    write_journal(&journal, &journal_file_path)?;
+    println!(
+        "Put back {} of {} paths.",
+        restored_count,
+        session.path_list.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn restore_session(session: &JournalSession) -> Result<usize, DirpError> {
+    use std::collections::HashMap;
+    use trash::os_limited::{list, restore_all};
+
+    // Each path may have been trashed more than once (by dirp, or by anything else). The
+    // latest is the one this session put there.
+    let mut trash_item_hash = HashMap::new();
+    for trash_item in list()? {
+        let path = trash_item.original_path();
+        if !session.path_list.contains(&path) {
+            continue;
+        }
+        let is_later = trash_item_hash
+            .get(&path)
+            .is_none_or(|latest: &trash::TrashItem| trash_item.time_deleted > latest.time_deleted);
+        if is_later {
+            trash_item_hash.insert(path, trash_item);
+        }
+    }
+
+    let mut trash_item_list = Vec::new();
+    for path in &session.path_list {
+        match trash_item_hash.remove(path) {
+            Some(trash_item) if trash_item.original_path().exists() => {
+                println!(
+                    "Leaving {} in the Trash: something else is there now.",
+                    path.to_string_lossy()
+                )
+            }
+            Some(trash_item) => trash_item_list.push(trash_item),
+            None => println!("{} is no longer in the Trash.", path.to_string_lossy()),
+        }
+    }
+
+    let restored_count = trash_item_list.len();
+    for trash_item in &trash_item_list {
+        info!(
+            "Putting {} back from the Trash",
+            trash_item.original_path().to_string_lossy()
+        );
+    }
+    restore_all(trash_item_list)?;
+
+    Ok(restored_count)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restore_session(_session: &JournalSession) -> Result<usize, DirpError> {
+    println!("Putting things back from the Trash is only supported on Linux.");
+    Ok(0)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo() -> Result<(), DirpError> {
+        // The trash crate finds the Trash from the environment, so the test is run again in
+        // a child process, with its own Trash under a throw away dir. That way nothing is
+        // put in, or taken from, the developer's Trash.
+        if env::var_os("DIRP_TEST_UNDO_CHILD").is_some() {
+            return undo_in_child();
+        }
+        let test_path = env::temp_dir().join("dirp_test_undo");
+        let _ = fs::remove_dir_all(&test_path);
+        fs::create_dir_all(test_path.join("data"))?;
+
+        let status = std::process::Command::new(env::current_exe()?)
+            .args(["journal::tests::test_undo", "--exact", "--test-threads=1"])
+            .env("DIRP_TEST_UNDO_CHILD", "1")
+            .env("XDG_DATA_HOME", test_path.join("data"))
+            .env("HOME", &test_path)
+            .status()?;
+        assert!(status.success(), "Error: Unexpected result.");
+        assert!(
+            test_path.join("data/Trash/info").exists(),
+            "Error: Unexpected result."
+        );
+
+        fs::remove_dir_all(&test_path)?;
+
+        Ok(())
+    }
+
+    fn undo_in_child() -> Result<(), DirpError> {
+        // The journal goes in a throw away dir. The files are trashed to the Trash set up
+        // by the parent, and put back from there.
+        let test_path = env::temp_dir().join("dirp_test_undo");
+        fs::create_dir_all(test_path.join("files/d"))?;
+        fs::write(test_path.join("files/a.txt"), "a")?;
+        fs::write(test_path.join("files/d/b.txt"), "b")?;
+
+        let path_list = vec![test_path.join("files/a.txt"), test_path.join("files/d")];
+        trash::delete_all(&path_list)?;
+        record_session_in(&test_path.join("journal"), &path_list)?;
+        assert!(
+            !test_path.join("files/a.txt").exists(),
+            "Error: Unexpected result."
+        );
+
+        let session = read_journal(&test_path.join("journal/journal.json"))
+            .session_list
+            .pop()
+            .unwrap();
+        assert_eq!(
+            session.path_list,
+            vec![test_path.join("files/a.txt"), test_path.join("files/d")],
+            "Error: Unexpected result."
+        );
+
+        assert_eq!(restore_session(&session)?, 2, "Error: Unexpected result.");
+        assert!(
+            test_path.join("files/a.txt").exists(),
+            "Error: Unexpected result."
+        );
+        assert!(
+            test_path.join("files/d/b.txt").exists(),
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_path_bytes() -> Result<(), DirpError> {
+        // A path that isn't UTF-8 is kept byte for byte.
+        let path = PathBuf::from(OsStr::from_bytes(b"/r/caf\xe9\n.txt"));
+        let journal = Journal {
+            session_list: vec![JournalSession {
+                time: 1,
+                path_list: vec![path.clone()],
+            }],
+        };
+        let contents = serde_json::to_string(&journal)?;
+        assert!(
+            contents.contains("\"2f722f636166e90a2e747874\""),
+            "Error: Unexpected result."
+        );
+        let read_journal: Journal = serde_json::from_str(&contents)?;
+        assert_eq!(
+            read_journal.session_list[0].path_list,
+            vec![path],
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
+}
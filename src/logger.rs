@@ -0,0 +1,81 @@
+use crate::types::*;
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    thread,
+};
+
+//
+// Writes the log (see the 'log' crate's macros, used throughout) to the file passed
+// with '--log'. Nothing is logged without it. A line per record, e.g.:
+//
+//     2023-02-12 10:15:02.118 WARN  [dirp-scan] dirp::utils: /root: Permission denied (os error 13)
+//
+// The file is appended to, and each line is written as it's logged, so a crash leaves
+// everything up to it behind.
+//
+struct FileLogger {
+    level: LevelFilter,
+    file: Mutex<File>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} [{}] {}: {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            thread::current().name().unwrap_or("unnamed"),
+            record.target(),
+            record.args()
+        );
+        if let Ok(mut file) = self.file.lock() {
+            // There is nowhere left to report a failure to log.
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+pub fn init_logger(log_path: &Path, level: LevelFilter) -> Result<(), DirpError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+    let logger = FileLogger {
+        level,
+        file: Mutex::new(file),
+    };
+    // Only fails if a logger is already set, and this is the only place one is.
+    let _ = log::set_boxed_logger(Box::new(logger));
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+pub fn parse_log_level(log_level: &str) -> Option<LevelFilter> {
+    match log_level {
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
@@ -1,13 +1,79 @@
 use cli::parse_args;
+use export::export_runloop;
+use journal::undo_runloop;
+use logger::init_logger;
+use plan::apply_runloop;
+use report::report_runloop;
 use terminal_ui::ui_runloop;
+use types::Command;
 
 mod cli;
+mod config;
 mod dirp_state;
+mod exclude;
+mod export;
+mod fs_watcher;
+mod journal;
+mod logger;
+mod ncdu;
+mod plan;
+mod report;
+mod scan_cache;
 mod terminal_ui;
+mod tree_view;
 mod tui_rs_boilerplate;
 mod types;
 mod utils;
 
 fn main() {
-    let _ = ui_runloop(parse_args());
+    let args = parse_args();
+    if let Some(log_path) = &args.log_path {
+        if let Err(error) = init_logger(log_path, args.log_level) {
+            eprintln!(
+                "dirp: Can not open the log file '{}': {:?}",
+                log_path.to_string_lossy(),
+                error
+            );
+            std::process::exit(-1);
+        }
+    }
+    log::info!(
+        "dirp {} started on {}",
+        env!("CARGO_PKG_VERSION"),
+        args.path.to_string_lossy()
+    );
+    match args.command {
+        Command::Ui => {
+            let _ = ui_runloop(args);
+        }
+        Command::Report { depth } => {
+            if let Err(error) = report_runloop(args, depth) {
+                eprintln!("dirp: {}", error);
+                std::process::exit(-1);
+            }
+        }
+        Command::Export {
+            ref export_path,
+            export_format,
+        } => {
+            let export_path = export_path.clone();
+            if let Err(error) = export_runloop(args, &export_path, export_format) {
+                eprintln!("dirp: {}", error);
+                std::process::exit(-1);
+            }
+        }
+        Command::Undo => {
+            if let Err(error) = undo_runloop(args) {
+                eprintln!("dirp: {}", error);
+                std::process::exit(-1);
+            }
+        }
+        Command::Apply { ref plan_path } => {
+            let plan_path = plan_path.clone();
+            if let Err(error) = apply_runloop(args, &plan_path) {
+                eprintln!("dirp: {}", error);
+                std::process::exit(-1);
+            }
+        }
+    }
 }
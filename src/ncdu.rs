@@ -0,0 +1,490 @@
+use crate::types::*;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+//
+// ncdu's JSON dump format (what 'ncdu -o' writes, and 'ncdu -f' reads), so dirp and
+// ncdu can read each other's scans. For example:
+//
+//     [1, 2, {"progname": "dirp", "progver": "0.1.0", "timestamp": 1676160000},
+//       [{"name": "/home/me/test", "mtime": 1676160000},
+//         {"name": "0.txt", "asize": 1010, "dsize": 4096, "ino": 1234, "mtime": ...},
+//         [{"name": "a", "mtime": ...},
+//           {"name": "1.txt", "asize": 1010, "dsize": 4096, "ino": 1235, "mtime": ...}
+//         ]
+//       ]
+//     ]
+//
+// A dir is an array of its own info, followed by its entries. Files are objects. The
+// root's name is its full path, everyone else's is just their name. Unlike dirp, ncdu
+// keeps each item's own size, and adds the totals up itself when it reads a dump. So
+// dirp writes its dirs with no size of their own, and totals them up on reading.
+//
+// The mapping between the two:
+//   symlinks are 'notreg', mount points are excluded 'otherfs', excluded paths are
+//   excluded 'pattern', hard linked files carry 'hlnkc' and 'nlink', and dirs that
+//   couldn't be read carry 'read_error'. An excluded dir is written as a dir with no
+//   entries. ncdu writes what it excludes as a bare object, with no size or type, as it
+//   never looked at it, and dirp reads those as excluded dirs. dirp's marks have no
+//   place in the format, and aren't kept.
+//
+const NCDU_MAJOR_VERSION: u64 = 1;
+const NCDU_MINOR_VERSION: u64 = 2;
+
+pub fn write_ncdu_export(dir: &Dir, path: &Path) -> Result<(), DirpError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let dump = json!([
+        NCDU_MAJOR_VERSION,
+        NCDU_MINOR_VERSION,
+        {
+            "progname": "dirp",
+            "progver": env!("CARGO_PKG_VERSION"),
+            "timestamp": timestamp,
+        },
+        ncdu_dir(dir, &dir.path.to_string_lossy(), 0),
+    ]);
+
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(&mut writer, &dump)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn ncdu_dir(dir: &Dir, name: &str, parent_dev: u64) -> Value {
+    // Dirs don't record their dev, so it's taken from the first file in them.
+    let dev = dir
+        .dir_obj_list
+        .iter()
+        .find_map(|fs_obj| match fs_obj {
+            FSObj::File(file) => Some(file.dev),
+            _ => None,
+        })
+        .unwrap_or(parent_dev);
+
+    let mut info = Map::new();
+    info.insert("name".to_string(), json!(name));
+    if dev != parent_dev {
+        info.insert("dev".to_string(), json!(dev));
+    }
+    info.insert("mtime".to_string(), json!(dir.mtime));
+    if dir.error_list.iter().any(|error| error.path == dir.path) {
+        info.insert("read_error".to_string(), json!(true));
+    }
+    if dir.is_excluded {
+        // Only the dir itself, but still as a dir. ncdu doesn't look inside excluded dirs.
+        info.insert("excluded".to_string(), json!("pattern"));
+        return Value::Array(vec![Value::Object(info)]);
+    }
+
+    let mut item_list = vec![Value::Object(info)];
+    for fs_obj in &dir.dir_obj_list {
+        item_list.push(ncdu_item(fs_obj, dev));
+    }
+
+    Value::Array(item_list)
+}
+
+fn ncdu_item(fs_obj: &FSObj, dev: u64) -> Value {
+    let name = file_name_of(fs_obj);
+    match fs_obj {
+        FSObj::Dir(dir) => ncdu_dir(dir, &name, dev),
+        FSObj::DirRef(_) => {
+            panic!("Invalid state.");
+        }
+        FSObj::File(file) => {
+            let mut info = Map::new();
+            info.insert("name".to_string(), json!(name));
+            info.insert("asize".to_string(), json!(file.size_in_bytes));
+            info.insert("dsize".to_string(), json!(file.disk_size_in_bytes));
+            if file.dev != dev {
+                info.insert("dev".to_string(), json!(file.dev));
+            }
+            info.insert("ino".to_string(), json!(file.ino));
+            if file.nlink > 1 {
+                info.insert("hlnkc".to_string(), json!(true));
+                info.insert("nlink".to_string(), json!(file.nlink));
+            }
+            if file.is_excluded {
+                info.insert("excluded".to_string(), json!("pattern"));
+            }
+            info.insert("mtime".to_string(), json!(file.mtime));
+            Value::Object(info)
+        }
+        FSObj::SymLink(sym_link) => {
+            let mut info = Map::new();
+            info.insert("name".to_string(), json!(name));
+            info.insert("asize".to_string(), json!(sym_link.size_in_bytes));
+            info.insert("dsize".to_string(), json!(sym_link.disk_size_in_bytes));
+            info.insert("notreg".to_string(), json!(true));
+            if sym_link.is_excluded {
+                info.insert("excluded".to_string(), json!("pattern"));
+            }
+            info.insert("mtime".to_string(), json!(sym_link.mtime));
+            Value::Object(info)
+        }
+        FSObj::MountPoint(_) => json!({
+            "name": name,
+            "excluded": "otherfs",
+        }),
+    }
+}
+
+fn file_name_of(fs_obj: &FSObj) -> String {
+    let path = match fs_obj {
+        FSObj::Dir(obj) => &obj.path,
+        FSObj::DirRef(obj) => &obj.path,
+        FSObj::File(obj) => &obj.path,
+        FSObj::SymLink(obj) => &obj.path,
+        FSObj::MountPoint(obj) => &obj.path,
+    };
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+//
+// Reads an ncdu dump back into a full tree, with dir sizes totalled the way dirp's
+// scan does (sub dirs and files, each hard linked inode counted once).
+//
+pub fn read_ncdu_export(file_path: &Path) -> Result<Dir, DirpError> {
+    let reader = BufReader::new(fs::File::open(file_path)?);
+    let dump: Value = serde_json::from_reader(reader)?;
+
+    let bad_dump = |reason: &str| DirpError::BadScanFile(format!("Not an ncdu dump: {}", reason));
+    let dump = dump.as_array().ok_or_else(|| bad_dump("not an array"))?;
+    if dump.len() < 4 {
+        return Err(bad_dump("too short"));
+    }
+    if dump[0].as_u64() != Some(NCDU_MAJOR_VERSION) {
+        return Err(bad_dump("unsupported major version"));
+    }
+
+    let mut inode_set = HashSet::new();
+    match import_item(&dump[3], Path::new(""), 0, &mut inode_set) {
+        Some(FSObj::Dir(mut root_dir)) => {
+            root_dir.is_open = true;
+            Ok(root_dir)
+        }
+        _ => Err(bad_dump("the root is not a dir")),
+    }
+}
+
+fn import_item(
+    item: &Value,
+    parent_path: &Path,
+    parent_dev: u64,
+    inode_set: &mut HashSet<(u64, u64)>,
+) -> Option<FSObj> {
+    let (info, sub_item_list) = match item {
+        Value::Array(item_list) => (item_list.first()?.as_object()?, Some(&item_list[1..])),
+        Value::Object(info) => (info, None),
+        _ => return None,
+    };
+
+    let name = info.get("name")?.as_str()?;
+    // The root's name is its path, and joining onto "" leaves it as is.
+    let path = parent_path.join(name);
+    let u64_field = |key: &str| info.get(key).and_then(Value::as_u64);
+    let size_in_bytes = u64_field("asize").unwrap_or(0);
+    let disk_size_in_bytes = u64_field("dsize").unwrap_or(0);
+    let dev = u64_field("dev").unwrap_or(parent_dev);
+    let mtime = info.get("mtime").and_then(Value::as_i64).unwrap_or(0);
+    let excluded = info.get("excluded").and_then(Value::as_str);
+
+    if let Some("otherfs") | Some("kernfs") = excluded {
+        return Some(FSObj::MountPoint(MountPoint { path }));
+    }
+    let is_excluded = excluded.is_some();
+    // What ncdu excludes it doesn't stat, so it has no size or type. A dir is the likelier.
+    let is_unexamined = is_excluded
+        && ["asize", "dsize", "ino", "notreg"]
+            .iter()
+            .all(|key| !info.contains_key(*key));
+
+    if sub_item_list.is_some() || is_unexamined {
+        let mut dir = Dir {
+            path,
+            size_in_bytes,
+            disk_size_in_bytes,
+            percent: 0,
+            is_marked: false,
+            is_excluded,
+            mtime,
+            is_open: false,
+            dir_obj_list: FSObjList::new(),
+            error_list: Vec::new(),
+        };
+        if info.get("read_error").and_then(Value::as_bool) == Some(true) {
+            dir.error_list.push(ScanError {
+                path: dir.path.clone(),
+                message: "couldn't be read (as recorded in the dump)".to_string(),
+            });
+        }
+        for sub_item in sub_item_list.unwrap_or_default() {
+            let fs_obj = match import_item(sub_item, &dir.path, dev, inode_set) {
+                Some(fs_obj) => fs_obj,
+                None => continue,
+            };
+            match &fs_obj {
+                FSObj::Dir(sub_dir) if !sub_dir.is_excluded => {
+                    dir.size_in_bytes += sub_dir.size_in_bytes;
+                    dir.disk_size_in_bytes += sub_dir.disk_size_in_bytes;
+                }
+                FSObj::File(file)
+                    if !file.is_excluded
+                        && (file.nlink <= 1 || inode_set.insert((file.dev, file.ino))) =>
+                {
+                    dir.size_in_bytes += file.size_in_bytes;
+                    dir.disk_size_in_bytes += file.disk_size_in_bytes;
+                }
+                _ => {}
+            }
+            dir.dir_obj_list.push(fs_obj);
+        }
+        return Some(FSObj::Dir(dir));
+    }
+
+    if info.get("notreg").and_then(Value::as_bool) == Some(true) {
+        return Some(FSObj::SymLink(SymLink {
+            path,
+            size_in_bytes,
+            disk_size_in_bytes,
+            percent: 0,
+            is_marked: false,
+            is_excluded,
+            mtime,
+        }));
+    }
+
+    let nlink = match u64_field("nlink") {
+        Some(nlink) => nlink,
+        // Older dumps only say that there is more than one link.
+        None if info.get("hlnkc").and_then(Value::as_bool) == Some(true) => 2,
+        None => 1,
+    };
+    Some(FSObj::File(File {
+        path,
+        size_in_bytes,
+        disk_size_in_bytes,
+        percent: 0,
+        is_marked: false,
+        is_excluded,
+        mtime,
+        dev,
+        ino: u64_field("ino").unwrap_or(0),
+        nlink,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scan_test_dir() -> Result<Dir, DirpError> {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let root_dir = dirp_state.full_state()?.dirp_state;
+        dirp_state.quit();
+
+        Ok(root_dir)
+    }
+
+    fn sorted_entry_list(dir: &Dir, entry_list: &mut Vec<(PathBuf, u64, u64)>) {
+        // (path, apparent size, disk usage) for everything under 'dir'.
+        for fs_obj in &dir.dir_obj_list {
+            match fs_obj {
+                FSObj::Dir(sub_dir) => {
+                    entry_list.push((
+                        sub_dir.path.clone(),
+                        sub_dir.size_in_bytes,
+                        sub_dir.disk_size_in_bytes,
+                    ));
+                    sorted_entry_list(sub_dir, entry_list);
+                }
+                FSObj::File(file) => entry_list.push((
+                    file.path.clone(),
+                    file.size_in_bytes,
+                    file.disk_size_in_bytes,
+                )),
+                FSObj::SymLink(sym_link) => entry_list.push((
+                    sym_link.path.clone(),
+                    sym_link.size_in_bytes,
+                    sym_link.disk_size_in_bytes,
+                )),
+                FSObj::MountPoint(mount_point) => entry_list.push((mount_point.path.clone(), 0, 0)),
+                FSObj::DirRef(_) => panic!("Unexpected fs obj."),
+            }
+        }
+        entry_list.sort();
+    }
+
+    #[test]
+    fn test_ncdu_round_trip() -> Result<(), DirpError> {
+        let mut root_dir = scan_test_dir()?;
+        // Neither adds to the root's size.
+        root_dir.dir_obj_list.push(FSObj::Dir(Dir {
+            path: PathBuf::from("./test/excluded"),
+            size_in_bytes: 0,
+            disk_size_in_bytes: 0,
+            percent: 0,
+            is_marked: false,
+            is_excluded: true,
+            mtime: 1,
+            is_open: false,
+            dir_obj_list: FSObjList::new(),
+            error_list: Vec::new(),
+        }));
+        root_dir.dir_obj_list.push(FSObj::MountPoint(MountPoint {
+            path: PathBuf::from("./test/mnt"),
+        }));
+
+        let dump_path = std::env::temp_dir().join("dirp_test_ncdu_round_trip.json");
+        write_ncdu_export(&root_dir, &dump_path)?;
+        let dump: Value = serde_json::from_str(&fs::read_to_string(&dump_path)?)?;
+        let read_root_dir = read_ncdu_export(&dump_path)?;
+
+        // The format.
+        assert_eq!(dump[0], json!(1), "Error: Unexpected result.");
+        assert_eq!(
+            dump[2]["progname"],
+            json!("dirp"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            dump[3][0]["name"],
+            json!("./test"),
+            "Error: Unexpected result."
+        );
+        let dump_c_d = dump[3]
+            .as_array()
+            .and_then(|item_list| {
+                item_list
+                    .iter()
+                    .find(|item| item[0]["name"] == json!("c"))?
+                    .as_array()?
+                    .iter()
+                    .find(|item| item[0]["name"] == json!("d"))
+            })
+            .expect("c/d not in the dump.");
+        assert_eq!(dump_c_d[0].get("asize"), None, "Error: Unexpected result.");
+        assert_eq!(dump_c_d[1]["name"], json!("5.txt"));
+        assert_eq!(dump_c_d[1]["asize"], json!(1010));
+        let dump_lnk = dump[3]
+            .as_array()
+            .and_then(|item_list| {
+                item_list
+                    .iter()
+                    .find(|item| item["name"] == json!("0.txt.lnk"))
+            })
+            .expect("0.txt.lnk not in the dump.");
+        assert_eq!(dump_lnk["notreg"], json!(true), "Error: Unexpected result.");
+        let dump_item = |name: &str| {
+            dump[3]
+                .as_array()
+                .and_then(|item_list| {
+                    item_list
+                        .iter()
+                        .find(|item| item["name"] == json!(name) || item[0]["name"] == json!(name))
+                })
+                .cloned()
+        };
+        assert_eq!(
+            dump_item("excluded"),
+            Some(json!([{"name": "excluded", "mtime": 1, "excluded": "pattern"}])),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            dump_item("mnt"),
+            Some(json!({"name": "mnt", "excluded": "otherfs"})),
+            "Error: Unexpected result."
+        );
+
+        // Reading it back gives the same tree, with the same totals.
+        assert_eq!(
+            read_root_dir.path,
+            Path::new("./test"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            read_root_dir.size_in_bytes, 8080,
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            read_root_dir.disk_size_in_bytes, root_dir.disk_size_in_bytes,
+            "Error: Unexpected result."
+        );
+        let mut entry_list = Vec::new();
+        sorted_entry_list(&root_dir, &mut entry_list);
+        let mut read_entry_list = Vec::new();
+        sorted_entry_list(&read_root_dir, &mut read_entry_list);
+        assert_eq!(read_entry_list, entry_list, "Error: Unexpected result.");
+
+        let read_excluded_dir = read_root_dir
+            .dir_obj_list
+            .iter()
+            .find_map(|fs_obj| match fs_obj {
+                FSObj::Dir(dir) if dir.path == Path::new("./test/excluded") => Some(dir),
+                _ => None,
+            });
+        assert!(
+            read_excluded_dir.is_some_and(|dir| dir.is_excluded),
+            "Error: Unexpected result."
+        );
+
+        // And writing that out again gives the same dump.
+        write_ncdu_export(&read_root_dir, &dump_path)?;
+        let dump_again: Value = serde_json::from_str(&fs::read_to_string(&dump_path)?)?;
+        assert_eq!(dump_again[3], dump[3], "Error: Unexpected result.");
+
+        fs::remove_file(&dump_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ncdu_excluded_import() -> Result<(), DirpError> {
+        // As ncdu writes what it excludes: no size, no type.
+        let dump_path = std::env::temp_dir().join("dirp_test_ncdu_excluded_import.json");
+        fs::write(
+            &dump_path,
+            r#"[1, 2, {"progname": "ncdu"}, [{"name": "/r"},
+                {"name": "node_modules", "excluded": "pattern"},
+                {"name": "a.o", "asize": 10, "ino": 2, "excluded": "pattern"},
+                {"name": "proc", "excluded": "kernfs"}]]"#,
+        )?;
+        let root_dir = read_ncdu_export(&dump_path)?;
+        fs::remove_file(&dump_path)?;
+
+        assert!(
+            matches!(&root_dir.dir_obj_list[0], FSObj::Dir(dir) if dir.is_excluded),
+            "Error: Unexpected result."
+        );
+        assert!(
+            matches!(&root_dir.dir_obj_list[1], FSObj::File(file) if file.is_excluded),
+            "Error: Unexpected result."
+        );
+        assert!(
+            matches!(&root_dir.dir_obj_list[2], FSObj::MountPoint(_)),
+            "Error: Unexpected result."
+        );
+        assert_eq!(root_dir.size_in_bytes, 0, "Error: Unexpected result.");
+
+        Ok(())
+    }
+}
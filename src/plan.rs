@@ -0,0 +1,518 @@
+use crate::dirp_state::{delete_paths, print_kept_path_list, LEFT_OUT_NOTE};
+use crate::journal::record_session;
+use crate::types::*;
+use crate::utils::human_readable_bytes;
+use dialoguer::{console::Term, theme::ColorfulTheme, FuzzySelect};
+use log::info;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::{self, FromStr},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+
+//
+// A removal plan: what 'x' would remove, written out instead of removed, so it can be
+// looked over (or checked in, and reviewed) first. For example:
+//
+//     # dirp removal plan for /home/me/src
+//     # Sizes are apparent sizes.
+//     # 2 paths, 3145728 bytes (3.15 MB), freeing 3145728 bytes (3.15 MB).
+//     #
+//     # size<TAB>mtime<TAB>path
+//     2097152	1676160000	/home/me/src/a/target
+//     1048576	1676150000	/home/me/src/b/core
+//
+// One line per marked path, sorted by path. A marked dir is listed once, with its total
+// size, and nothing under it is. Other than the 'Sizes are' line, comments are for
+// people, and aren't read back. Paths are written as they are on disk, so a name that
+// isn't UTF-8 is kept as it is, except that a backslash, tab, carriage return or line
+// break in one is written as '\\', '\t', '\r' or '\n', so each line holds one path.
+//
+// 'dirp apply <plan>' removes what the plan lists, but only those paths whose size and
+// mtime are still what the plan says, so a plan made on one host can be applied on
+// others. With '-x', each path is sized, and removed, without leaving the file system
+// it's on, as the scan that made the plan did. A dir's size in the plan counts each hard
+// linked file under it once, whether or not it's linked elsewhere in the tree too, as
+// that is the size 'apply' can measure again.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanEntry {
+    pub path: PathBuf,
+    pub size_in_bytes: u64,
+    pub mtime: i64,
+}
+
+//
+// Writes the plan to 'plan_path', or to stdout if there isn't one.
+//
+pub fn write_plan(
+    root_path: &Path,
+    size_mode: SizeMode,
+    marked_summary: &MarkedSummary,
+    plan_entry_list: &Vec<PlanEntry>,
+    plan_path: Option<&Path>,
+) -> Result<(), DirpError> {
+    let mut writer: Box<dyn Write> = match plan_path {
+        Some(plan_path) => Box::new(BufWriter::new(fs::File::create(plan_path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    writeln!(writer, "# dirp removal plan for {}", root_path.display())?;
+    writeln!(writer, "{}", size_mode_line(size_mode))?;
+    writeln!(
+        writer,
+        "# {} paths, {} bytes ({}), freeing {} bytes ({}).",
+        plan_entry_list.len(),
+        marked_summary.size_in_bytes,
+        human_readable_bytes(marked_summary.size_in_bytes),
+        marked_summary.reclaimable_size_in_bytes,
+        human_readable_bytes(marked_summary.reclaimable_size_in_bytes)
+    )?;
+    writeln!(writer, "#")?;
+    writeln!(writer, "# size\tmtime\tpath")?;
+    for plan_entry in plan_entry_list {
+        write!(
+            writer,
+            "{}\t{}\t",
+            plan_entry.size_in_bytes, plan_entry.mtime
+        )?;
+        writer.write_all(&escape_path(&plan_entry.path))?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn escape_path(path: &Path) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            byte => escaped.push(*byte),
+        }
+    }
+    escaped
+}
+
+fn unescape_path(escaped: &[u8]) -> Option<PathBuf> {
+    // None if there's a backslash escape_path() wouldn't have written.
+    let mut path = Vec::new();
+    let mut byte_iter = escaped.iter();
+    while let Some(byte) = byte_iter.next() {
+        if *byte != b'\\' {
+            path.push(*byte);
+            continue;
+        }
+        path.push(match byte_iter.next()? {
+            b'\\' => b'\\',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'n' => b'\n',
+            _ => return None,
+        });
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&path)))
+}
+
+fn size_mode_line(size_mode: SizeMode) -> &'static str {
+    match size_mode {
+        SizeMode::Apparent => "# Sizes are apparent sizes.",
+        SizeMode::Disk => "# Sizes are disk usage.",
+    }
+}
+
+//
+// Reads a plan written by write_plan(). Returns what its sizes are, and its entries.
+//
+pub fn read_plan(plan_path: &Path) -> Result<(SizeMode, Vec<PlanEntry>), DirpError> {
+    // Read as bytes, as paths needn't be UTF-8.
+    let contents = fs::read(plan_path)?;
+    let mut size_mode = SizeMode::Apparent;
+    let mut plan_entry_list = Vec::new();
+
+    for (index, line) in contents.split(|byte| *byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line == size_mode_line(SizeMode::Disk).as_bytes() {
+            size_mode = SizeMode::Disk;
+            continue;
+        }
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+
+        let error = || {
+            DirpError::BadPlanFile(format!(
+                "{}:{}: can't make sense of '{}'.",
+                plan_path.to_string_lossy(),
+                index + 1,
+                String::from_utf8_lossy(line)
+            ))
+        };
+        let mut field_iter = line.splitn(3, |byte| *byte == b'\t');
+        let size_in_bytes = parse_field::<u64>(field_iter.next()).ok_or_else(error)?;
+        let mtime = parse_field::<i64>(field_iter.next()).ok_or_else(error)?;
+        let path = field_iter
+            .next()
+            .filter(|field| !field.is_empty())
+            .and_then(unescape_path)
+            .ok_or_else(error)?;
+
+        plan_entry_list.push(PlanEntry {
+            path,
+            size_in_bytes,
+            mtime,
+        });
+    }
+
+    Ok((size_mode, plan_entry_list))
+}
+
+fn parse_field<T: FromStr>(field: Option<&[u8]>) -> Option<T> {
+    str::from_utf8(field?).ok()?.parse::<T>().ok()
+}
+
+//
+// Removes what the plan at 'plan_path' lists, skipping (and saying so) anything that
+// has changed since the plan was made. Asks first, unless told not to.
+//
+pub fn apply_runloop(args: Args, plan_path: &Path) -> Result<(), DirpError> {
+    let (size_mode, plan_entry_list) = read_plan(plan_path)?;
+    let scan_options = args.scan_options;
+
+    let mut path_list = Vec::new();
+    let mut size_in_bytes = 0;
+    for plan_entry in &plan_entry_list {
+        match plan_entry_drift(plan_entry, size_mode, &scan_options)? {
+            Some(drift) => {
+                info!("Skipping {}: {}", plan_entry.path.display(), drift);
+                println!("Skipping {}: {}.", plan_entry.path.display(), drift)
+            }
+            None => {
+                path_list.push(plan_entry.path.clone());
+                size_in_bytes += plan_entry.size_in_bytes;
+            }
+        }
+    }
+    if path_list.is_empty() {
+        println!("Nothing in the plan still matches, so nothing was removed.");
+        return Ok(());
+    }
+
+    println!();
+    for path in &path_list {
+        println!("{}", path.display());
+    }
+    println!();
+    let size = format!(
+        "{} bytes ({})",
+        size_in_bytes,
+        human_readable_bytes(size_in_bytes)
+    );
+    if args.is_dry_run {
+        println!(
+            "These {} paths, {}, still match the plan. This is a dry run, so nothing was removed.",
+            path_list.len(),
+            size
+        );
+        return Ok(());
+    }
+
+    if !args.is_confirmed {
+        match args.remove_mode {
+            RemoveMode::Trash => {
+                println!(
+                    "Move these {} paths, {}, to the Trash?",
+                    path_list.len(),
+                    size
+                )
+            }
+            RemoveMode::Permanent => {
+                println!(
+                    "Permanently delete these {} paths, destroying {}? This can't be undone.",
+                    path_list.len(),
+                    size
+                );
+                println!("{}", LEFT_OUT_NOTE);
+            }
+        }
+        let items = vec!["No", "Yes"];
+        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+            .items(&items)
+            .default(0)
+            .interact_on_opt(&Term::stdout())?;
+        if selection != Some(1) {
+            return Ok(());
+        }
+    }
+
+    match args.remove_mode {
+        RemoveMode::Trash => {
+            for path in &path_list {
+                info!("Moving {} to the Trash", path.display());
+            }
+            let result = trash::delete_all(&path_list);
+            record_session(&path_list)?;
+            result?
+        }
+        RemoveMode::Permanent => print_kept_path_list(&delete_paths(&path_list, &scan_options)?),
+    }
+    println!("Removed {} paths, {}.", path_list.len(), size);
+
+    Ok(())
+}
+
+//
+// How the path in 'plan_entry' no longer matches it, if it doesn't. A dir is sized the
+// way a scan with the same options, as dirp did when the plan was made, adds it up.
+//
+fn plan_entry_drift(
+    plan_entry: &PlanEntry,
+    size_mode: SizeMode,
+    scan_options: &ScanOptions,
+) -> Result<Option<String>, DirpError> {
+    let meta_data = match fs::symlink_metadata(&plan_entry.path) {
+        Ok(meta_data) => meta_data,
+        Err(_error) => return Ok(Some("it no longer exists".to_string())),
+    };
+    if meta_data.st_mtime() != plan_entry.mtime {
+        return Ok(Some(format!(
+            "its mtime was {}, and is now {}",
+            plan_entry.mtime,
+            meta_data.st_mtime()
+        )));
+    }
+
+    let size_in_bytes = if meta_data.is_dir() {
+        dir_size(
+            &plan_entry.path,
+            meta_data.st_dev(),
+            size_mode,
+            scan_options,
+            &mut HashSet::new(),
+        )
+    } else {
+        meta_data_size(&meta_data, size_mode)
+    };
+    if size_in_bytes != plan_entry.size_in_bytes {
+        return Ok(Some(format!(
+            "its size was {} bytes, and is now {} bytes",
+            plan_entry.size_in_bytes, size_in_bytes
+        )));
+    }
+
+    Ok(None)
+}
+
+fn meta_data_size(meta_data: &fs::Metadata, size_mode: SizeMode) -> u64 {
+    match size_mode {
+        SizeMode::Apparent => meta_data.st_size(),
+        SizeMode::Disk => meta_data.st_blocks() * 512,
+    }
+}
+
+//
+// The size of the dir at 'dir_path', as its plan entry was sized: what the exclude rules
+// leave out isn't counted, nor are symlinks, or what can't be read, and a hard linked
+// file is counted once within the dir ('inode_set'), however many paths it has in it.
+// With '-x', other file systems than 'dev' aren't counted either.
+//
+fn dir_size(
+    dir_path: &Path,
+    dev: u64,
+    size_mode: SizeMode,
+    scan_options: &ScanOptions,
+    inode_set: &mut HashSet<(u64, u64)>,
+) -> u64 {
+    let read_dir = match fs::read_dir(dir_path) {
+        Ok(read_dir) => read_dir,
+        Err(_error) => return 0,
+    };
+    let ignore_file_chain = scan_options.exclude_rules.ignore_file_chain(dir_path);
+    let mut size_in_bytes = 0;
+    for dir_entry in read_dir.flatten() {
+        if std::env::consts::OS == "macos" && dir_entry.file_name() == ".DS_Store" {
+            continue;
+        }
+        let path = dir_entry.path();
+        let meta_data = match fs::symlink_metadata(&path) {
+            Ok(meta_data) => meta_data,
+            Err(_error) => continue,
+        };
+        if scan_options
+            .exclude_rules
+            .is_excluded(&path, meta_data.is_dir(), &ignore_file_chain)
+        {
+            continue;
+        }
+        if meta_data.is_dir() {
+            if scan_options.root_dev.is_none() || meta_data.st_dev() == dev {
+                size_in_bytes += dir_size(&path, dev, size_mode, scan_options, inode_set);
+            }
+        } else if meta_data.is_file()
+            && (meta_data.st_nlink() <= 1
+                || inode_set.insert((meta_data.st_dev(), meta_data.st_ino())))
+        {
+            size_in_bytes += meta_data_size(&meta_data, size_mode);
+        }
+    }
+
+    size_in_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_plan() -> Result<(), DirpError> {
+        let plan_path = std::env::temp_dir().join("dirp_test_write_plan.txt");
+        let plan_entry_list = vec![
+            PlanEntry {
+                path: PathBuf::from("/r/a"),
+                size_in_bytes: 2000,
+                mtime: 10,
+            },
+            PlanEntry {
+                path: PathBuf::from("/r/b c"),
+                size_in_bytes: 1000,
+                mtime: 20,
+            },
+        ];
+        let marked_summary = MarkedSummary {
+            size_in_bytes: 3000,
+            reclaimable_size_in_bytes: 2000,
+        };
+        write_plan(
+            Path::new("/r"),
+            SizeMode::Apparent,
+            &marked_summary,
+            &plan_entry_list,
+            Some(&plan_path),
+        )?;
+        let plan = fs::read_to_string(&plan_path)?;
+        fs::remove_file(&plan_path)?;
+
+        assert_eq!(
+            plan,
+            "# dirp removal plan for /r\n\
+             # Sizes are apparent sizes.\n\
+             # 2 paths, 3000 bytes (3.00 KB), freeing 2000 bytes (2.00 KB).\n\
+             #\n\
+             # size\tmtime\tpath\n\
+             2000\t10\t/r/a\n\
+             1000\t20\t/r/b c\n"
+        );
+
+        // Reading it back gives what was written.
+        fs::write(&plan_path, plan)?;
+        let (size_mode, read_plan_entry_list) = read_plan(&plan_path)?;
+        fs::remove_file(&plan_path)?;
+        assert_eq!(size_mode, SizeMode::Apparent, "Error: Unexpected result.");
+        assert_eq!(
+            read_plan_entry_list, plan_entry_list,
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_escaped_path() -> Result<(), DirpError> {
+        // Line breaks and tabs can't be taken for the end of a line or a field.
+        let plan_path = std::env::temp_dir().join("dirp_test_plan_escaped_path.txt");
+        let plan_entry_list = vec![
+            PlanEntry {
+                path: PathBuf::from("/r/a\n100\t1\t/r/b"),
+                size_in_bytes: 2000,
+                mtime: 10,
+            },
+            PlanEntry {
+                path: PathBuf::from("/r/c\\n\r"),
+                size_in_bytes: 1000,
+                mtime: 20,
+            },
+        ];
+        write_plan(
+            Path::new("/r"),
+            SizeMode::Apparent,
+            &MarkedSummary::default(),
+            &plan_entry_list,
+            Some(&plan_path),
+        )?;
+        let plan = fs::read_to_string(&plan_path)?;
+        let (_, read_plan_entry_list) = read_plan(&plan_path)?;
+        fs::remove_file(&plan_path)?;
+
+        assert!(
+            plan.ends_with("2000\t10\t/r/a\\n100\\t1\\t/r/b\n1000\t20\t/r/c\\\\n\\r\n"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            read_plan_entry_list, plan_entry_list,
+            "Error: Unexpected result."
+        );
+
+        // A backslash that isn't an escape means the plan has been tampered with.
+        fs::write(&plan_path, "1\t1\t/r/a\\x\n")?;
+        let result = read_plan(&plan_path);
+        fs::remove_file(&plan_path)?;
+        assert!(result.is_err(), "Error: Unexpected result.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_entry_drift() -> Result<(), DirpError> {
+        let dir_path = std::env::temp_dir().join("dirp_test_plan_entry_drift");
+        let _ = fs::remove_dir_all(&dir_path);
+        fs::create_dir_all(dir_path.join("d"))?;
+        fs::write(dir_path.join("d/1.txt"), vec![0u8; 100])?;
+        fs::write(dir_path.join("2.txt"), vec![0u8; 200])?;
+
+        let plan_entry = |name: &str, size_in_bytes: u64| -> Result<PlanEntry, DirpError> {
+            let path = dir_path.join(name);
+            Ok(PlanEntry {
+                mtime: fs::symlink_metadata(&path)?.st_mtime(),
+                path,
+                size_in_bytes,
+            })
+        };
+        let d = plan_entry("d", 100)?;
+        let file_2 = plan_entry("2.txt", 200)?;
+        let file_2_drifted = plan_entry("2.txt", 300)?;
+        let scan_options = ScanOptions::default();
+        let drift = |plan_entry: &PlanEntry| {
+            plan_entry_drift(plan_entry, SizeMode::Apparent, &scan_options)
+        };
+
+        assert_eq!(drift(&d)?, None, "Error: Unexpected result.");
+        assert_eq!(drift(&file_2)?, None, "Error: Unexpected result.");
+        assert!(
+            drift(&file_2_drifted)?.is_some(),
+            "Error: Unexpected result."
+        );
+
+        // Something new deep in a dir changes its size.
+        fs::write(dir_path.join("d/1.txt"), vec![0u8; 150])?;
+        assert!(drift(&d)?.is_some(), "Error: Unexpected result.");
+
+        fs::remove_dir_all(&dir_path)?;
+        assert!(drift(&file_2)?.is_some(), "Error: Unexpected result.");
+
+        Ok(())
+    }
+}
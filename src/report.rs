@@ -0,0 +1,197 @@
+use crate::types::*;
+use crate::utils::*;
+use std::io::{self, Write};
+
+//
+// The non-interactive way to run dirp. Waits for the scan to finish, then prints
+// the sized tree to stdout, largest first, down to 'depth' levels below the root.
+// For example, at depth 1:
+//
+//      8.08 KB  100%  /home/me/test
+//      3.03 KB   37%   a/
+//      2.02 KB   25%   c/
+//      1.01 KB   12%   0.txt
+//
+// Anything that couldn't be read is listed after the tree, as those sizes are short.
+//
+pub fn report_runloop(args: Args, depth: u32) -> Result<(), DirpError> {
+    let dirp_state = DirpState::new(
+        args.path.clone(),
+        args.size_mode,
+        args.scan_source,
+        args.scan_options,
+    );
+
+    let state_response = dirp_state.full_state()?;
+    dirp_state.quit();
+
+    let mut line_list = Vec::new();
+    report_lines(
+        &mut FSObj::Dir(state_response.dirp_state),
+        1,
+        depth + 1,
+        state_response.size_mode,
+        &mut line_list,
+    );
+    let scan_error_list = &state_response.scan_error_list;
+    if !scan_error_list.is_empty() {
+        line_list.push("".to_string());
+        line_list.push(format!(
+            "{} paths couldn't be read, so the sizes above may be short:",
+            scan_error_list.len()
+        ));
+        for error in scan_error_list {
+            line_list.push(format!("  {}: {}", error.path.display(), error.message));
+        }
+    }
+
+    let mut stdout = io::stdout().lock();
+    for line in line_list {
+        if let Err(error) = writeln!(stdout, "{}", line) {
+            if error.kind() == io::ErrorKind::BrokenPipe {
+                // e.g. piped into 'head'.
+                return Ok(());
+            }
+            return Err(error.into());
+        }
+    }
+
+    Ok(())
+}
+
+fn report_lines(
+    fs_obj: &mut FSObj,
+    level: u32,
+    max_level: u32,
+    size_mode: SizeMode,
+    line_list: &mut Vec<String>,
+) {
+    if level > max_level {
+        return;
+    }
+
+    let indent = indent_prefix_for_level(level);
+    match fs_obj {
+        FSObj::Dir(dir) => {
+            // The root is shown by the path it was given, everything else by name.
+            let name = match level {
+                1 => dir.path.to_string_lossy().to_string(),
+                _ => format!("{}/", file_name(&dir.path).unwrap_or_default()),
+            };
+            line_list.push(report_line(
+                dir.size_in_bytes_for(size_mode),
+                Some(dir.percent),
+                &indent,
+                &name,
+                dir.is_excluded,
+            ));
+
+            dir.dir_obj_list.sort_by(|a, b| {
+                b.size_in_bytes_for(size_mode)
+                    .cmp(&a.size_in_bytes_for(size_mode))
+            });
+
+            for child_obj in &mut dir.dir_obj_list {
+                report_lines(child_obj, level + 1, max_level, size_mode, line_list);
+            }
+        }
+        FSObj::DirRef(_) => {
+            panic!("Invalid state.");
+        }
+        FSObj::File(file) => {
+            let mut name = file_name(&file.path).unwrap_or_default();
+            if file.nlink > 1 {
+                name = format!("{} ({} links)", name, file.nlink);
+            }
+            line_list.push(report_line(
+                file.size_in_bytes_for(size_mode),
+                Some(file.percent),
+                &indent,
+                &name,
+                file.is_excluded,
+            ));
+        }
+        FSObj::SymLink(sym_link) => {
+            let name = format!("{} ->", file_name(&sym_link.path).unwrap_or_default());
+            line_list.push(report_line(
+                sym_link.size_in_bytes_for(size_mode),
+                Some(sym_link.percent),
+                &indent,
+                &name,
+                sym_link.is_excluded,
+            ));
+        }
+        FSObj::MountPoint(mount_point) => {
+            let name = format!(
+                "{}/ (mount point)",
+                file_name(&mount_point.path).unwrap_or_default()
+            );
+            line_list.push(report_line(0, None, &indent, &name, false));
+        }
+    }
+}
+
+fn report_line(
+    size_in_bytes: u64,
+    percent: Option<u8>,
+    indent: &str,
+    name: &str,
+    is_excluded: bool,
+) -> String {
+    let size = match percent {
+        Some(_) => human_readable_bytes(size_in_bytes),
+        None => "".to_string(),
+    };
+    let percent = match percent {
+        Some(percent) => format!("{}%", percent),
+        None => "".to_string(),
+    };
+    let excluded = match is_excluded {
+        true => " (excluded)",
+        false => "",
+    };
+
+    format!(
+        "{:>12}  {:>4}  {}{}{}",
+        size, percent, indent, name, excluded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_report_lines() -> Result<(), DirpError> {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let state_response = dirp_state.full_state()?;
+        dirp_state.quit();
+
+        let mut line_list = Vec::new();
+        report_lines(
+            &mut FSObj::Dir(state_response.dirp_state),
+            1,
+            2,
+            SizeMode::Apparent,
+            &mut line_list,
+        );
+        println!("{:#?}", line_list);
+
+        // The root, then its five entries largest first. Nothing deeper.
+        assert_eq!(line_list.len(), 6, "Error: Unexpected result.");
+        assert_eq!(line_list[0], "     8.08 KB  100%  ./test");
+        assert_eq!(line_list[1], "     3.03 KB   37%   a/");
+        assert!(line_list[2].ends_with(" c/") || line_list[2].ends_with(" e/"));
+        assert!(line_list[3].ends_with(" c/") || line_list[3].ends_with(" e/"));
+        assert_eq!(line_list[4], "     1.01 KB   12%   0.txt");
+        assert!(line_list[5].ends_with("   0.txt.lnk ->"));
+
+        Ok(())
+    }
+}
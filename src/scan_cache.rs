@@ -0,0 +1,344 @@
+use crate::types::*;
+use crate::utils::fs_obj_for_meta_data;
+use home::home_dir;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::MetadataExt;
+
+//
+// What was found in each dir, the last time dirp scanned it, so a dir that hasn't
+// changed since doesn't have to be listed again. Kept in $XDG_CACHE_HOME/dirp
+// (~/.cache/dirp if XDG_CACHE_HOME isn't set), one file per root path.
+//
+// A cached dir is only used if its inode and mtime are what they were, and so are the
+// ignore files that decide what in it is excluded. A dir's mtime changes when entries
+// are added, removed or renamed, but not when a file in it grows, so only the names are
+// taken from the cache. Each entry is still read (stat), for its size. Rescanning a dir
+// ('r') always lists it.
+//
+// The cache file is JSON, so a dir is only cached if its path, and every name in it,
+// is UTF-8. Others are read from disk every time.
+//
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub struct ScanCache {
+    file_path: PathBuf,
+    // The scan options the cache was built with. Any change and it is thrown away, as
+    // they decide what a dir's entries are.
+    options_key: String,
+    // Read from the cache file. Each entry is used at most once, and moved to
+    // 'scanned_dir_hash', so any later scan of the dir reads it from disk.
+    cached_dir_hash: Mutex<HashMap<String, CachedDir>>,
+    // Every dir scanned this run, from disk or from the cache. This is what gets saved.
+    scanned_dir_hash: Mutex<HashMap<String, CachedDir>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    options_key: String,
+    dir_hash: HashMap<String, CachedDir>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedDir {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    // See ExcludeRules::ignore_file_key().
+    ignore_key: String,
+    entry_list: Vec<CachedEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedEntry {
+    name: String,
+    excluded: bool,
+}
+
+pub fn cache_file_path(root_path: &Path) -> Option<PathBuf> {
+    let cache_dir_path = match env::var_os("XDG_CACHE_HOME") {
+        Some(cache_home) if !cache_home.is_empty() => PathBuf::from(cache_home).join("dirp"),
+        _ => home_dir()?.join(".cache").join("dirp"),
+    };
+
+    // The root path, made into a file name.
+    let file_name = root_path
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    Some(cache_dir_path.join(format!("{}.json", file_name)))
+}
+
+impl ScanCache {
+    //
+    // Loads the cache at 'file_path'. A missing, unreadable or out of date cache file
+    // just means an empty cache.
+    //
+    pub fn load(file_path: PathBuf, options_key: String) -> ScanCache {
+        let cached_dir_hash = fs::File::open(&file_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, CacheFile>(BufReader::new(file)).ok())
+            .filter(|cache_file| {
+                cache_file.version == CACHE_VERSION && cache_file.options_key == options_key
+            })
+            .map(|cache_file| cache_file.dir_hash)
+            .unwrap_or_default();
+
+        ScanCache {
+            file_path,
+            options_key,
+            cached_dir_hash: Mutex::new(cached_dir_hash),
+            scanned_dir_hash: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //
+    // The entries of the dir at 'dir_path', if it is cached and hasn't changed since.
+    // 'meta_data' is the dir's, read before anything else is done with it.
+    //
+    pub fn take(
+        &self,
+        dir_path: &Path,
+        meta_data: &fs::Metadata,
+        is_open: bool,
+        scan_options: &ScanOptions,
+    ) -> Option<FSObjList> {
+        let dir_path_key = dir_path.to_str()?;
+        let cached_dir = self.cached_dir_hash.lock().unwrap().remove(dir_path_key)?;
+        if cached_dir.dev != meta_data.st_dev()
+            || cached_dir.ino != meta_data.st_ino()
+            || cached_dir.mtime != meta_data.st_mtime()
+            || cached_dir.mtime_nsec != meta_data.st_mtime_nsec()
+            || cached_dir.ignore_key != scan_options.exclude_rules.ignore_file_key(dir_path)
+        {
+            return None;
+        }
+
+        let mut fs_obj_list = FSObjList::new();
+        for entry in &cached_dir.entry_list {
+            let path = dir_path.join(&entry.name);
+            // Gone, so the listing is out of date after all.
+            let entry_meta_data = fs::symlink_metadata(&path).ok()?;
+            if let Some(fs_obj) = fs_obj_for_meta_data(
+                path,
+                &entry_meta_data,
+                is_open,
+                entry.excluded,
+                scan_options,
+            ) {
+                fs_obj_list.push(fs_obj);
+            }
+        }
+        self.scanned_dir_hash
+            .lock()
+            .unwrap()
+            .insert(dir_path_key.to_string(), cached_dir);
+
+        Some(fs_obj_list)
+    }
+
+    //
+    // Records what was just read from the dir at 'dir_path'. 'meta_data' is the dir's,
+    // read before the dir was.
+    //
+    pub fn insert(
+        &self,
+        dir_path: &Path,
+        meta_data: &fs::Metadata,
+        fs_obj_list: &FSObjList,
+        scan_options: &ScanOptions,
+    ) {
+        let dir_path_key = match dir_path.to_str() {
+            Some(dir_path_key) => dir_path_key.to_string(),
+            None => return,
+        };
+
+        // A dir changed within a second of being read could change again without its
+        // mtime moving (on file systems with coarse timestamps). Don't trust it.
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as i64);
+        let entry_list: Option<Vec<CachedEntry>> =
+            fs_obj_list.iter().map(entry_for_fs_obj).collect();
+        let entry_list = match entry_list {
+            Some(entry_list) if now - meta_data.st_mtime() > 1 => entry_list,
+            _ => {
+                self.scanned_dir_hash.lock().unwrap().remove(&dir_path_key);
+                return;
+            }
+        };
+
+        let cached_dir = CachedDir {
+            dev: meta_data.st_dev(),
+            ino: meta_data.st_ino(),
+            mtime: meta_data.st_mtime(),
+            mtime_nsec: meta_data.st_mtime_nsec(),
+            ignore_key: scan_options.exclude_rules.ignore_file_key(dir_path),
+            entry_list,
+        };
+        self.scanned_dir_hash
+            .lock()
+            .unwrap()
+            .insert(dir_path_key, cached_dir);
+    }
+
+    //
+    // Writes the cache back to its file. If the scan didn't complete, anything cached
+    // that it didn't get to is kept for next time.
+    //
+    pub fn save(&self, is_scan_complete: bool) -> Result<(), DirpError> {
+        let mut dir_hash = self.scanned_dir_hash.lock().unwrap().clone();
+        if !is_scan_complete {
+            for (dir_path, cached_dir) in self.cached_dir_hash.lock().unwrap().iter() {
+                dir_hash
+                    .entry(dir_path.clone())
+                    .or_insert_with(|| cached_dir.clone());
+            }
+        }
+        let cache_file = CacheFile {
+            version: CACHE_VERSION,
+            options_key: self.options_key.clone(),
+            dir_hash,
+        };
+
+        if let Some(cache_dir_path) = self.file_path.parent() {
+            fs::create_dir_all(cache_dir_path)?;
+        }
+        // Written aside, then moved into place, so a reader never sees half a file.
+        let temp_file_path = self.file_path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(fs::File::create(&temp_file_path)?);
+        serde_json::to_writer(&mut writer, &cache_file)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_file_path, &self.file_path)?;
+
+        Ok(())
+    }
+}
+
+fn entry_for_fs_obj(fs_obj: &FSObj) -> Option<CachedEntry> {
+    // None if the name isn't UTF-8.
+    let name = |path: &Path| path.file_name()?.to_str().map(str::to_string);
+    let entry = match fs_obj {
+        FSObj::File(file) => CachedEntry {
+            name: name(&file.path)?,
+            excluded: file.is_excluded,
+        },
+        FSObj::SymLink(sym_link) => CachedEntry {
+            name: name(&sym_link.path)?,
+            excluded: sym_link.is_excluded,
+        },
+        FSObj::DirRef(dir_ref) => CachedEntry {
+            name: name(&dir_ref.path)?,
+            excluded: dir_ref.is_excluded,
+        },
+        FSObj::MountPoint(mount_point) => CachedEntry {
+            name: name(&mount_point.path)?,
+            excluded: false,
+        },
+        FSObj::Dir(_) => return None,
+    };
+    Some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::scan_dir_path;
+    use std::sync::{mpsc::channel, Arc};
+    use std::time::Duration;
+
+    fn scan_file_sizes(dir_path: &Path, scan_options: &ScanOptions) -> Vec<u64> {
+        let (sender, receiver) = channel();
+        scan_dir_path(dir_path.to_path_buf(), true, scan_options, sender).unwrap();
+
+        let mut size_list = Vec::new();
+        if let Ok(DirpStateMessage::DirScanMessage(dir)) = receiver.recv() {
+            for fs_obj in dir.dir_obj_list {
+                if let FSObj::File(file) = fs_obj {
+                    size_list.push(file.size_in_bytes);
+                }
+            }
+        }
+        size_list.sort();
+        size_list
+    }
+
+    #[test]
+    fn test_scan_cache() -> Result<(), DirpError> {
+        let dir_path = env::temp_dir().join("dirp_test_scan_cache");
+        let cache_file_path = env::temp_dir().join("dirp_test_scan_cache.json");
+        let _ = fs::remove_dir_all(&dir_path);
+        let _ = fs::remove_file(&cache_file_path);
+        fs::create_dir_all(&dir_path)?;
+        fs::write(dir_path.join("a"), vec![0u8; 100])?;
+
+        // Age the dir, so it is trusted.
+        let mtime = SystemTime::now() - Duration::from_secs(60);
+        fs::File::open(&dir_path)?.set_modified(mtime)?;
+
+        let scan_options = ScanOptions {
+            scan_cache: Some(Arc::new(ScanCache::load(
+                cache_file_path.clone(),
+                "".to_string(),
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(scan_file_sizes(&dir_path, &scan_options), vec![100]);
+        scan_options.scan_cache.as_ref().unwrap().save(true)?;
+
+        // The file grows, and a new file's creation is hidden by putting the dir's mtime
+        // back. The dir looks unchanged, so its listing comes from the cache, but the
+        // size that has changed in place is read.
+        fs::write(dir_path.join("a"), vec![0u8; 200])?;
+        fs::write(dir_path.join("b"), vec![0u8; 10])?;
+        fs::File::open(&dir_path)?.set_modified(mtime)?;
+        let scan_options = ScanOptions {
+            scan_cache: Some(Arc::new(ScanCache::load(
+                cache_file_path.clone(),
+                "".to_string(),
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(scan_file_sizes(&dir_path, &scan_options), vec![200]);
+        // Only once.
+        assert_eq!(scan_file_sizes(&dir_path, &scan_options), vec![10, 200]);
+        scan_options.scan_cache.as_ref().unwrap().save(true)?;
+
+        // A changed .dirpignore changes what is excluded, so the dir is read again, even
+        // though it looks unchanged.
+        fs::write(dir_path.join(".dirpignore"), "b\n")?;
+        fs::File::open(&dir_path)?.set_modified(mtime)?;
+        let scan_options = ScanOptions {
+            scan_cache: Some(Arc::new(ScanCache::load(
+                cache_file_path.clone(),
+                "".to_string(),
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(scan_file_sizes(&dir_path, &scan_options), vec![2, 200]);
+
+        // Different options, different cache.
+        let scan_cache = ScanCache::load(cache_file_path.clone(), "--gitignore".to_string());
+        assert!(scan_cache.cached_dir_hash.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir_path)?;
+        fs::remove_file(&cache_file_path)?;
+
+        Ok(())
+    }
+}
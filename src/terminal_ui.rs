@@ -1,13 +1,27 @@
+use crate::export::write_json_export;
+use crate::tree_view::TreeView;
 use crate::tui_rs_boilerplate::AppRow;
-use crate::tui_rs_boilerplate::{step_app, App};
+use crate::tui_rs_boilerplate::{step_app, table_row_count, App, MAX_ERROR_PANEL_LINES};
 use crate::types::*;
-use crate::utils::*;
+use chrono::Local;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor::Show,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::{error::Error, io};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    error::Error,
+    io, panic,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use std::{sync::mpsc::Sender, thread};
 use tui::{backend::CrosstermBackend, Terminal};
 
@@ -18,15 +32,111 @@ fn input_thread_spawn(user_sender: Sender<UserMessage>) {
     thread::spawn(move || {
         let result = input_thread(user_sender);
         if let Err(error) = result {
+            log::error!("input_thread: {:?}", error);
             panic!("input_thread: {:?}.", error);
         }
     });
 }
 
+// Set while the terminal is in raw mode, on the alternate screen.
+static IS_TERMINAL_TAKEN_OVER: AtomicBool = AtomicBool::new(false);
+
+fn take_over_terminal() -> io::Result<()> {
+    enable_raw_mode()?;
+    IS_TERMINAL_TAKEN_OVER.store(true, Ordering::SeqCst);
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+    Ok(())
+}
+
+//
+// Puts the terminal back the way it was found. Called from whichever thread gets there
+// first (the UI on its way out, the panic hook, or the signal thread), and only does
+// anything the first time.
+//
+fn restore_terminal() {
+    if IS_TERMINAL_TAKEN_OVER.swap(false, Ordering::SeqCst) {
+        let _ = disable_raw_mode();
+        let _ = execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            DisableMouseCapture,
+            Show
+        );
+    }
+}
+
+//
+// A panic in any thread leaves the UI with nothing behind it, so restore the terminal
+// (before the panic message is printed, so it can be read), and exit.
+//
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        restore_terminal();
+        log::error!("{}", panic_info);
+        default_hook(panic_info);
+        exit(-1);
+    }));
+}
+
+//
+// The terminal is in raw mode, so Ctrl-C arrives as a key (see input_thread()). This
+// is for the signals that still get through, e.g. from 'kill', or the terminal closing.
+// The handler stays in place once the UI has gone, as removing it would leave these
+// signals ignored.
+//
+fn signal_thread_spawn() -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            restore_terminal();
+            log::info!("Exiting on signal {}", signal);
+            eprintln!("dirp: Exiting on signal {}.", signal);
+            exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
 fn input_thread(user_sender: Sender<UserMessage>) -> Result<(), DirpError> {
+    // While a query is being typed, keys go into it.
+    let mut is_query_mode = false;
+
     loop {
         match event::read()? {
+            Event::Key(key)
+                if key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                user_sender.send(UserMessage::Quit)?;
+                return Ok(());
+            }
+            Event::Key(key) if is_query_mode => match key.code {
+                KeyCode::Enter => {
+                    is_query_mode = false;
+                    user_sender.send(UserMessage::QueryDone)?;
+                }
+                KeyCode::Esc => {
+                    is_query_mode = false;
+                    user_sender.send(UserMessage::QueryCancel)?;
+                }
+                KeyCode::Backspace => user_sender.send(UserMessage::QueryBackspace)?,
+                KeyCode::Down | KeyCode::Tab => user_sender.send(UserMessage::QueryNext)?,
+                KeyCode::Up | KeyCode::BackTab => user_sender.send(UserMessage::QueryPrevious)?,
+                KeyCode::Char(c) => user_sender.send(UserMessage::QueryChar(c))?,
+                _ => {}
+            },
             Event::Key(key) => match key.code {
+                KeyCode::Char('/') => {
+                    is_query_mode = true;
+                    user_sender.send(UserMessage::StartSearch)?;
+                }
+                KeyCode::Char('F') => {
+                    is_query_mode = true;
+                    user_sender.send(UserMessage::StartFilter)?;
+                }
+                KeyCode::Tab => user_sender.send(UserMessage::QueryNext)?,
+                KeyCode::BackTab => user_sender.send(UserMessage::QueryPrevious)?,
                 KeyCode::Down => user_sender.send(UserMessage::Next)?,
                 KeyCode::Up => user_sender.send(UserMessage::Previous)?,
                 KeyCode::Left => user_sender.send(UserMessage::CloseDir)?,
@@ -39,6 +149,12 @@ fn input_thread(user_sender: Sender<UserMessage>) -> Result<(), DirpError> {
                 KeyCode::Char('f') => user_sender.send(UserMessage::ToggleDir)?,
                 KeyCode::Char('d') => user_sender.send(UserMessage::MarkPath)?,
                 KeyCode::Char('u') => user_sender.send(UserMessage::UnmarkPath)?,
+                KeyCode::Char('a') => user_sender.send(UserMessage::ToggleSizeMode)?,
+                KeyCode::Char('e') => user_sender.send(UserMessage::Export)?,
+                KeyCode::Char('r') => user_sender.send(UserMessage::Rescan)?,
+                KeyCode::Char('s') => user_sender.send(UserMessage::SavePlan)?,
+                KeyCode::Char('P') => user_sender.send(UserMessage::ToggleRemoveMode)?,
+                KeyCode::Char('E') => user_sender.send(UserMessage::ToggleErrorPanel)?,
 
                 KeyCode::Char('x') => {
                     user_sender.send(UserMessage::RemoveMarked)?;
@@ -57,77 +173,56 @@ fn input_thread(user_sender: Sender<UserMessage>) -> Result<(), DirpError> {
     }
 }
 
-fn dirp_state_to_i_state(
-    fs_obj: &mut FSObj,
-    level: u32,
-    i_state: &mut Vec<IntermediateState>,
-) -> Option<()> {
-    match fs_obj {
-        FSObj::Dir(dir) => {
-            let flipper = match dir.is_open {
-                true => "⏷",
-                false => "⏵",
-            };
-            let name = file_name(&dir.path)?;
-            let name = format!("{}{} {}", indent_prefix_for_level(level), flipper, name);
-            let size = human_readable_bytes(dir.size_in_bytes);
-            let percent = format!("{}%", dir.percent);
-
-            i_state.push(IntermediateState {
-                ui_row: vec![name, percent, size],
-                is_marked: dir.is_marked,
-                path: dir.path.clone(),
-            });
-
-            dir.dir_obj_list
-                .sort_by(|a, b| b.size_in_bytes().cmp(&a.size_in_bytes()));
-
-            for child_obj in &mut dir.dir_obj_list {
-                dirp_state_to_i_state(child_obj, level + 1, i_state);
-            }
-        }
-        FSObj::DirRef(dir_ref) => {
-            let name = file_name(&dir_ref.path)?;
-            let name = format!("{}> {}", indent_prefix_for_level(level), name);
-            let size = human_readable_bytes(dir_ref.size_in_bytes);
-            let percent = format!("{}%", dir_ref.percent);
-
-            i_state.push(IntermediateState {
-                ui_row: vec![name, percent, size],
-                is_marked: dir_ref.is_marked,
-                path: dir_ref.path.clone(),
-            });
-        }
-        FSObj::File(file) => {
-            let name = file_name(&file.path)?;
-            let name = format!("{}  {}", indent_prefix_for_level(level), name);
-            let size = human_readable_bytes(file.size_in_bytes);
-            let percent = format!("{}%", file.percent);
-
-            i_state.push(IntermediateState {
-                ui_row: vec![name, percent, size],
-                is_marked: file.is_marked,
-                path: file.path.clone(),
-            });
+#[derive(PartialEq)]
+enum QueryMode {
+    // Jump between the rows that match.
+    Search,
+    // Show only what matches.
+    Filter,
+}
+
+//
+// The first row, going from 'from_index' in the given direction (and round), whose name
+// matches 'query'.
+//
+fn find_matching_row(
+    tree_view: &mut TreeView,
+    matcher: &SkimMatcherV2,
+    query: &str,
+    from_index: usize,
+    is_forward: bool,
+) -> Option<usize> {
+    let row_count = tree_view.row_count();
+    if row_count == 0 || query.is_empty() {
+        return None;
+    }
+    let from_index = from_index % row_count;
+    let index_list = tree_view.matching_rows(matcher, query);
+    match is_forward {
+        true => {
+            let position = index_list.partition_point(|index| *index < from_index);
+            index_list.get(position).or(index_list.first()).copied()
         }
-        FSObj::SymLink(sym_link) => {
-            let name = file_name(&sym_link.path)?;
-            let name = format!("{}  {}", indent_prefix_for_level(level), name);
-            let size = human_readable_bytes(sym_link.size_in_bytes);
-            let percent = format!("{}%", sym_link.percent);
-
-            i_state.push(IntermediateState {
-                ui_row: vec![name, percent, size],
-                is_marked: sym_link.is_marked,
-                path: sym_link.path.clone(),
-            });
+        false => {
+            let position = index_list.partition_point(|index| *index <= from_index);
+            match position {
+                0 => index_list.last().copied(),
+                _ => Some(index_list[position - 1]),
+            }
         }
-    };
+    }
+}
 
-    Some(())
+fn find_row_for_path(tree_view: &mut TreeView, path: &Path) -> Option<usize> {
+    path.ancestors()
+        .find_map(|ancestor_path| tree_view.index_of(ancestor_path))
 }
 
-fn i_state_to_app_state<'a>(i_state: &'a Vec<IntermediateState>) -> Vec<AppRow<'a>> {
+// Built past the bottom of the table, so a terminal grown since it was measured is
+// still filled.
+const ROW_MARGIN: usize = 4;
+
+fn i_state_to_app_state(i_state: &[IntermediateState]) -> Vec<AppRow<'_>> {
     let mut result = Vec::new();
 
     for item in i_state {
@@ -138,6 +233,8 @@ fn i_state_to_app_state<'a>(i_state: &'a Vec<IntermediateState>) -> Vec<AppRow<'
                 item.ui_row[2].as_str(),
             ],
             is_marked: item.is_marked,
+            is_excluded: item.is_excluded,
+            has_error: item.has_error,
         });
     }
 
@@ -145,47 +242,78 @@ fn i_state_to_app_state<'a>(i_state: &'a Vec<IntermediateState>) -> Vec<AppRow<'
 }
 
 pub fn ui_runloop(args: Args) -> Result<(), Box<dyn Error>> {
-    let path = args.path.to_string_lossy().to_string();
+    let mut title = match args.scan_source {
+        ScanSource::Disk => args.path.display().to_string(),
+        ScanSource::Snapshot(_) => format!("{} (saved scan)", args.path.display()),
+    };
+    if args.is_dry_run {
+        title = format!("{} (dry run)", title);
+    }
 
     // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    install_panic_hook();
+    signal_thread_spawn()?;
+    take_over_terminal()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
 
     // App state is maintained in a background thread.
     // This kicks that thread off.
-    let dirp_state = DirpState::new(path.clone());
+    let dirp_state = DirpState::new(
+        args.path.clone(),
+        args.size_mode,
+        args.scan_source,
+        args.scan_options,
+    );
 
     // There is another thread to handle user input.
     input_thread_spawn(dirp_state.user_sender.clone());
 
-    let mut i_state_list = Vec::new();
+    let mut tree_view = TreeView::new(args.path.clone(), args.size_mode);
+    // The selected row, and the first on screen, out of all of them.
     let mut state = 0;
+    let mut scroll_offset = 0;
+    // The selection is kept by path, as rows move about when sizes change.
+    let mut selected_path: Option<PathBuf> = None;
+    let mut size_mode = args.size_mode;
+    let mut remove_mode = args.remove_mode;
+    let mut marked_summary = MarkedSummary::default();
+    let mut scan_progress = ScanProgress::default();
+    let mut status_message = None;
+    let mut is_error_panel_open = false;
+    let matcher = SkimMatcherV2::default();
+    let mut query_mode = None;
+    // What is being typed, or was last searched for.
+    let mut query = String::new();
+    let mut filter = None;
 
     let mut do_remove_marked = false;
 
-    let app_state = i_state_to_app_state(&i_state_list);
-    let app = App::new(path.clone(), app_state);
+    let app = App::new(title.clone(), size_mode, Vec::new());
 
     let _ = step_app(&mut terminal, app);
 
     // This loop handles messages from the input thread, and the state
     // thread.
-    loop {
+    let result = loop {
         let mut do_next = false;
         let mut do_prev = false;
+        let mut is_query_changed = false;
 
         match dirp_state.user_receiver.recv() {
             Ok(user_message) => match user_message {
-                UserMessage::GetStateResponse(user_message) => {
-                    i_state_list.clear();
-                    dirp_state_to_i_state(
-                        &mut FSObj::Dir(user_message.dirp_state),
-                        1,
-                        &mut i_state_list,
-                    )
-                    .expect("err");
+                UserMessage::StateUpdate(state_update) => {
+                    size_mode = state_update.size_mode;
+                    marked_summary = state_update.marked_summary;
+                    scan_progress = state_update.scan_progress;
+                    tree_view.apply(
+                        state_update.dir_change_list,
+                        state_update.filter_change,
+                        size_mode,
+                    );
+                    state = match &selected_path {
+                        Some(path) => find_row_for_path(&mut tree_view, path).unwrap_or(0),
+                        None => 0,
+                    };
                 }
                 UserMessage::Next => {
                     do_next = true;
@@ -194,68 +322,338 @@ pub fn ui_runloop(args: Args) -> Result<(), Box<dyn Error>> {
                     do_prev = true;
                 }
                 UserMessage::OpenDir => {
-                    dirp_state.send(DirpStateMessage::OpenDir(i_state_list[state].path.clone()));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::OpenDir(path));
+                    }
                 }
                 UserMessage::CloseDir => {
-                    dirp_state.send(DirpStateMessage::CloseDir(i_state_list[state].path.clone()));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::CloseDir(path));
+                    }
                 }
                 UserMessage::ToggleDir => {
-                    dirp_state.send(DirpStateMessage::ToggleDir(
-                        i_state_list[state].path.clone(),
-                    ));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::ToggleDir(path));
+                    }
                 }
                 UserMessage::MarkPath => {
-                    dirp_state.send(DirpStateMessage::MarkPath(i_state_list[state].path.clone()));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::MarkPath(path));
+                    }
                 }
                 UserMessage::UnmarkPath => {
-                    dirp_state.send(DirpStateMessage::UnmarkPath(
-                        i_state_list[state].path.clone(),
-                    ));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::UnmarkPath(path));
+                    }
                 }
                 UserMessage::ToggleMarkPath => {
-                    dirp_state.send(DirpStateMessage::ToggleMarkPath(
-                        i_state_list[state].path.clone(),
-                    ));
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::ToggleMarkPath(path));
+                    }
+                }
+                UserMessage::ToggleSizeMode => {
+                    dirp_state.send(DirpStateMessage::ToggleSizeMode);
+                }
+                UserMessage::Rescan => {
+                    if let Some(path) = selected_path.clone() {
+                        dirp_state.send(DirpStateMessage::Rescan(path));
+                    }
+                }
+                UserMessage::Export => {
+                    // Answered once the scan is complete.
+                    dirp_state.send(DirpStateMessage::GetFullState);
+                    status_message = Some("Exporting when the scan completes...".to_string());
+                }
+                UserMessage::SavePlan => {
+                    let plan_path = Local::now()
+                        .format("dirp-plan-%Y%m%d-%H%M%S.txt")
+                        .to_string();
+                    dirp_state.send(DirpStateMessage::SavePlan(PathBuf::from(plan_path)));
+                }
+                UserMessage::ToggleErrorPanel => {
+                    is_error_panel_open = !is_error_panel_open;
+                }
+                UserMessage::StartSearch => {
+                    query_mode = Some(QueryMode::Search);
+                    query.clear();
+                }
+                UserMessage::StartFilter => {
+                    query_mode = Some(QueryMode::Filter);
+                    query = filter.clone().unwrap_or_default();
+                }
+                UserMessage::QueryChar(c) => {
+                    query.push(c);
+                    is_query_changed = true;
+                }
+                UserMessage::QueryBackspace => {
+                    query.pop();
+                    is_query_changed = true;
+                }
+                UserMessage::QueryNext | UserMessage::QueryPrevious => {
+                    let is_forward = matches!(user_message, UserMessage::QueryNext);
+                    if query_mode == Some(QueryMode::Filter) {
+                        // Everything shown matches, so just move.
+                        do_next = is_forward;
+                        do_prev = !is_forward;
+                    } else {
+                        let from_index = match is_forward {
+                            true => state + 1,
+                            false => state + tree_view.row_count().max(1) - 1,
+                        };
+                        if let Some(index) = find_matching_row(
+                            &mut tree_view,
+                            &matcher,
+                            &query,
+                            from_index,
+                            is_forward,
+                        ) {
+                            state = index;
+                        }
+                    }
+                }
+                UserMessage::QueryDone => {
+                    query_mode = None;
+                }
+                UserMessage::QueryCancel => {
+                    if query_mode == Some(QueryMode::Filter) {
+                        filter = None;
+                        dirp_state.send(DirpStateMessage::SetFilter(None));
+                    }
+                    query_mode = None;
+                }
+                UserMessage::Status(message) => {
+                    status_message = Some(message);
+                }
+                UserMessage::ToggleRemoveMode => {
+                    remove_mode = remove_mode.toggled();
+                    status_message = match remove_mode {
+                        RemoveMode::Trash => {
+                            Some("'x' moves marked files to the Trash".to_string())
+                        }
+                        RemoveMode::Permanent => {
+                            Some("'x' deletes marked files permanently".to_string())
+                        }
+                    };
                 }
                 UserMessage::RemoveMarked => {
                     do_remove_marked = true;
-                    break;
+                    break Ok(());
+                }
+                UserMessage::GetFullStateResponse(user_message) => {
+                    // The export asked for below.
+                    let export_path = Local::now()
+                        .format("dirp-export-%Y%m%d-%H%M%S.json")
+                        .to_string();
+                    status_message = match write_json_export(
+                        &user_message.dirp_state,
+                        user_message.size_mode,
+                        Path::new(&export_path),
+                    ) {
+                        Ok(()) => Some(format!("Exported to {}", export_path)),
+                        Err(error) => Some(format!("Export failed: {}", error)),
+                    };
                 }
-                UserMessage::Quit => break,
+                UserMessage::Quit => break Ok(()),
             },
             Err(error) => {
                 panic!("recv() error: {}", error);
             }
         }
 
-        let app_state = i_state_to_app_state(&i_state_list);
-        let mut app = App::new(path.clone(), app_state);
+        if is_query_changed {
+            if query_mode == Some(QueryMode::Filter) {
+                filter = Some(query.clone()).filter(|query| !query.is_empty());
+                dirp_state.send(DirpStateMessage::SetFilter(filter.clone()));
+            } else if let Some(index) =
+                find_matching_row(&mut tree_view, &matcher, &query, state, true)
+            {
+                state = index;
+            }
+        }
 
-        app.set_selected(state);
-        if do_next {
-            app.next();
+        let row_count = tree_view.row_count();
+        if do_next && state + 1 < row_count {
+            state += 1;
         }
-        if do_prev {
-            app.previous();
+        if do_prev && state > 0 {
+            state -= 1;
         }
-        state = app.selected();
+        state = state.min(row_count.saturating_sub(1));
+        selected_path = tree_view.path_at(state);
+
+        // Only the rows on screen are built, however many there are.
+        let height = match terminal.size() {
+            Ok(size) => size.height,
+            Err(error) => break Err(error),
+        };
+        let visible_row_count =
+            table_row_count(height, tree_view.scan_error_count(), is_error_panel_open);
+        scroll_offset = scroll_offset.min(state);
+        if state >= scroll_offset + visible_row_count {
+            scroll_offset = state + 1 - visible_row_count;
+        }
+        let i_state_list = tree_view
+            .rows(scroll_offset..row_count.min(scroll_offset + visible_row_count + ROW_MARGIN));
+        let app_state = i_state_to_app_state(&i_state_list);
+        let mut app = App::new(title.clone(), size_mode, app_state);
+        app.marked_summary = marked_summary;
+        app.scan_progress = scan_progress;
+        app.status_message = status_message.clone();
+        app.remove_mode = remove_mode;
+        app.scan_error_count = tree_view.scan_error_count();
+        if is_error_panel_open {
+            app.scan_error_list = tree_view.scan_error_list(MAX_ERROR_PANEL_LINES);
+        }
+        app.is_error_panel_open = is_error_panel_open;
+        app.query_line = match query_mode {
+            Some(QueryMode::Search) => Some(format!("/{}", query)),
+            Some(QueryMode::Filter) => Some(format!("Filter: {}", query)),
+            None => None,
+        };
+        app.filter = filter.clone();
+
+        app.set_selected(state - scroll_offset);
 
         let _ = step_app(&mut terminal, app);
-    }
+    };
 
     // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
-    if do_remove_marked {
-        dirp_state.send(DirpStateMessage::RemoveMarked);
-        let _ = dirp_state.thread_handle.join();
-    }
+    restore_terminal();
+    // Panics from here on are on a normal terminal, and can take their default course.
+    let _ = panic::take_hook();
+
+    let exit_message = match (do_remove_marked, args.is_dry_run) {
+        (false, _) => DirpStateMessage::Quit,
+        (true, true) => DirpStateMessage::WritePlan(args.plan_path),
+        (true, false) => DirpStateMessage::RemoveMarked(remove_mode),
+    };
+    finish_dirp_state(dirp_state, exit_message);
+
+    result?;
 
     Ok(())
 }
+
+//
+// Passes on how the user left ('exit_message'), and waits for the state thread to act on
+// it and finish. The scan cache is saved as it does, however the UI was left.
+//
+fn finish_dirp_state(dirp_state: DirpState, exit_message: DirpStateMessage) {
+    dirp_state.send(exit_message);
+    let _ = dirp_state.thread_handle.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan_cache::ScanCache;
+    use std::{env, fs, sync::Arc};
+
+    #[test]
+    fn test_find_row_for_path() {
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let mut tree_view = TreeView::new(PathBuf::from("./test"), SizeMode::Apparent);
+        loop {
+            if let UserMessage::StateUpdate(state_update) = dirp_state.recv() {
+                tree_view.apply(
+                    state_update.dir_change_list,
+                    state_update.filter_change,
+                    state_update.size_mode,
+                );
+                if state_update.scan_progress.is_complete {
+                    break;
+                }
+            }
+        }
+        dirp_state.quit();
+
+        // Found wherever it's been sorted to.
+        assert_eq!(
+            find_row_for_path(&mut tree_view, Path::new("./test/a")),
+            Some(1),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_row_for_path(&mut tree_view, Path::new("./test/0.txt.lnk")),
+            Some(5),
+            "Error: Unexpected result."
+        );
+        // Not shown, so its nearest ancestor that is.
+        assert_eq!(
+            find_row_for_path(&mut tree_view, Path::new("./test/a/1.txt")),
+            Some(1),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_row_for_path(&mut tree_view, Path::new("./test/c/d/5.txt")),
+            tree_view.index_of(Path::new("./test/c")),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_row_for_path(&mut tree_view, Path::new("/elsewhere")),
+            None,
+            "Error: Unexpected result."
+        );
+
+        // Searches wrap around, either way.
+        let matcher = SkimMatcherV2::default();
+        assert_eq!(
+            find_matching_row(&mut tree_view, &matcher, "lnk", 0, true),
+            Some(5),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_matching_row(&mut tree_view, &matcher, "lnk", 6, true),
+            Some(5),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_matching_row(&mut tree_view, &matcher, "test", 3, false),
+            Some(0),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            find_matching_row(&mut tree_view, &matcher, "nothing_like_it", 0, true),
+            None,
+            "Error: Unexpected result."
+        );
+    }
+
+    #[test]
+    fn test_finish_dirp_state() {
+        let cache_file_path = env::temp_dir().join("dirp_test_finish_dirp_state.json");
+        let _ = fs::remove_file(&cache_file_path);
+
+        let scan_options = ScanOptions {
+            scan_cache: Some(Arc::new(ScanCache::load(
+                cache_file_path.clone(),
+                "".to_string(),
+            ))),
+            ..Default::default()
+        };
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            scan_options,
+        );
+        loop {
+            if let UserMessage::StateUpdate(state_update) = dirp_state.recv() {
+                if state_update.scan_progress.is_complete {
+                    break;
+                }
+            }
+        }
+
+        // Left the way 'q' leaves, the cache is still saved.
+        finish_dirp_state(dirp_state, DirpStateMessage::Quit);
+        assert!(cache_file_path.exists(), "Error: Unexpected result.");
+
+        let _ = fs::remove_file(&cache_file_path);
+    }
+}
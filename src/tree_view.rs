@@ -0,0 +1,832 @@
+use crate::types::*;
+use crate::utils::*;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+//
+// The UI's copy of the state thread's tree, and the rows shown for it. The state thread
+// only sends what has changed (see StateUpdate), which apply() brings in.
+//
+// Rows are only built when asked for (see row()), so a dir with a few hundred thousand
+// entries open costs no more to draw than the rows on screen. What each dir adds to the
+// rows, its entries in the order they are shown and the row each starts on, is kept in
+// 'rows_hash'. Finding a row is then a binary search in each dir on the way down to it.
+// When something changes, only its entry in each dir above it is put back in place (see
+// note_changed()), so a change deep in the tree doesn't sort every dir on the way to it
+// again.
+//
+pub struct TreeView {
+    root_path: PathBuf,
+    dir_hash: DirHash,
+    size_mode: SizeMode,
+    // While a filter is on, the paths it leaves shown. Dirs with any of them in are shown
+    // open.
+    filter_path_set: Option<HashSet<PathBuf>>,
+    rows_hash: HashMap<PathBuf, DirRows>,
+    // The entries of each dir that have changed since its rows were worked out, by the
+    // dir's path.
+    changed_entry_hash: HashMap<PathBuf, HashSet<PathBuf>>,
+    // The last query searched for, and the rows that match it, until the rows change.
+    match_cache: Option<(String, Vec<usize>)>,
+    // What couldn't be read, by the dir it was listed in.
+    scan_error_hash: BTreeMap<PathBuf, Vec<ScanError>>,
+    scan_error_count: usize,
+}
+
+struct DirRows {
+    is_open: bool,
+    // Its shown entries, biggest first, then in the order it lists them. Empty while it's
+    // closed.
+    entry_rows_list: Vec<EntryRows>,
+    // The row each of those starts on, counting from the row after the dir's own.
+    row_offset_list: Vec<usize>,
+    // Its own row, and everything shown under it.
+    row_count: usize,
+    // Where each of its entries is in its dir_obj_list, by path. Empty while it's closed.
+    entry_index_hash: HashMap<PathBuf, usize>,
+}
+
+struct EntryRows {
+    entry_index: usize,
+    // As sorted by.
+    size_in_bytes: u64,
+    row_count: usize,
+}
+
+impl EntryRows {
+    fn sort_key(&self) -> (Reverse<u64>, usize) {
+        (Reverse(self.size_in_bytes), self.entry_index)
+    }
+}
+
+impl TreeView {
+    pub fn new(root_path: PathBuf, size_mode: SizeMode) -> TreeView {
+        TreeView {
+            root_path,
+            dir_hash: DirHash::new(),
+            size_mode,
+            filter_path_set: None,
+            rows_hash: HashMap::new(),
+            changed_entry_hash: HashMap::new(),
+            match_cache: None,
+            scan_error_hash: BTreeMap::new(),
+            scan_error_count: 0,
+        }
+    }
+
+    pub fn apply(
+        &mut self,
+        dir_change_list: Vec<DirChange>,
+        filter_change: Option<FilterChange>,
+        size_mode: SizeMode,
+    ) {
+        if size_mode == self.size_mode && dir_change_list.is_empty() && filter_change.is_none() {
+            return;
+        }
+        self.match_cache = None;
+        if size_mode != self.size_mode {
+            // Everything is in a different order.
+            self.size_mode = size_mode;
+            self.clear_rows();
+        }
+        match filter_change {
+            Some(FilterChange::Off) => {
+                self.filter_path_set = None;
+                self.clear_rows();
+            }
+            Some(FilterChange::On(path_list)) => {
+                self.filter_path_set = Some(path_list.into_iter().collect());
+                self.clear_rows();
+            }
+            Some(FilterChange::Changed {
+                shown_path_list,
+                hidden_path_list,
+            }) => {
+                if let Some(filter_path_set) = &mut self.filter_path_set {
+                    for path in &hidden_path_list {
+                        filter_path_set.remove(path);
+                    }
+                    filter_path_set.extend(shown_path_list.iter().cloned());
+                }
+                for path in hidden_path_list.iter().chain(&shown_path_list) {
+                    self.note_changed(path);
+                }
+            }
+            None => {}
+        }
+        for dir_change in dir_change_list {
+            let (path, is_listing_changed) = match &dir_change {
+                DirChange::Listed(dir) => {
+                    self.set_scan_errors(&dir.path, dir.error_list.clone());
+                    (dir.path.clone(), true)
+                }
+                DirChange::Removed(path) => {
+                    self.set_scan_errors(path, Vec::new());
+                    (path.clone(), true)
+                }
+                DirChange::Updated(dir_update) => (
+                    dir_update.path.clone(),
+                    // Opened, or closed.
+                    self.dir_hash
+                        .get(&dir_update.path)
+                        .is_some_and(|dir| dir.is_open != dir_update.is_open),
+                ),
+            };
+            self.dir_hash.apply(dir_change);
+            if is_listing_changed {
+                self.rows_hash.remove(&path);
+            }
+            self.note_changed(&path);
+        }
+        // Kept track of as it's changed, but note_changed() has seen to it.
+        self.dir_hash.take_changed_path_set();
+    }
+
+    pub fn scan_error_count(&self) -> usize {
+        self.scan_error_count
+    }
+
+    //
+    // The first 'max_count' of what couldn't be read, by the dir it was in.
+    //
+    pub fn scan_error_list(&self, max_count: usize) -> Vec<ScanError> {
+        self.scan_error_hash
+            .values()
+            .flatten()
+            .take(max_count)
+            .cloned()
+            .collect()
+    }
+
+    fn set_scan_errors(&mut self, dir_path: &Path, error_list: Vec<ScanError>) {
+        // A dir listed again brings all of its errors, so those it had go.
+        if let Some(old_error_list) = self.scan_error_hash.remove(dir_path) {
+            self.scan_error_count -= old_error_list.len();
+        }
+        if !error_list.is_empty() {
+            self.scan_error_count += error_list.len();
+            self.scan_error_hash
+                .insert(dir_path.to_path_buf(), error_list);
+        }
+    }
+
+    pub fn row_count(&mut self) -> usize {
+        let root_path = self.root_path.clone();
+        self.dir_row_count(&root_path)
+    }
+
+    pub fn row(&mut self, index: usize) -> Option<IntermediateState> {
+        let total_bytes = self
+            .dir_hash
+            .get(&self.root_path)?
+            .size_in_bytes_for(self.size_mode) as f64;
+        let (dir_path, entry_index, level) = self.locate(index)?;
+        let dir = self.dir_hash.get(&dir_path)?;
+        let row = match entry_index {
+            Some(entry_index) => entry_row(
+                &dir.dir_obj_list[entry_index],
+                level,
+                self.size_mode,
+                total_bytes,
+            ),
+            None => dir_row(dir, self.is_open(dir), level, self.size_mode, total_bytes),
+        };
+
+        Some(row)
+    }
+
+    pub fn rows(&mut self, range: Range<usize>) -> Vec<IntermediateState> {
+        range.filter_map(|index| self.row(index)).collect()
+    }
+
+    pub fn path_at(&mut self, index: usize) -> Option<PathBuf> {
+        let (dir_path, entry_index, _) = self.locate(index)?;
+        match entry_index {
+            Some(entry_index) => {
+                let dir = self.dir_hash.get(&dir_path)?;
+                Some(fs_obj_path(&dir.dir_obj_list[entry_index]).to_path_buf())
+            }
+            None => Some(dir_path),
+        }
+    }
+
+    //
+    // The row showing 'path', if it's shown.
+    //
+    pub fn index_of(&mut self, path: &Path) -> Option<usize> {
+        if !path.starts_with(&self.root_path) || self.row_count() == 0 {
+            return None;
+        }
+        let mut dir_path = self.root_path.clone();
+        let mut index = 0;
+        while dir_path != path {
+            // The entry of this dir that is, or leads to, 'path'.
+            let entry_path = dir_path.join(path.strip_prefix(&dir_path).ok()?.components().next()?);
+            let dir_rows = self.rows_hash.get(&dir_path)?;
+            let dir = self.dir_hash.get(&dir_path)?;
+            let entry_index = *dir_rows.entry_index_hash.get(&entry_path)?;
+            let fs_obj = &dir.dir_obj_list[entry_index];
+            let sort_key = (Reverse(self.entry_size(fs_obj)), entry_index);
+            let position = dir_rows
+                .entry_rows_list
+                .partition_point(|entry_rows| entry_rows.sort_key() < sort_key);
+            if dir_rows.entry_rows_list.get(position)?.entry_index != entry_index {
+                // Not shown.
+                return None;
+            }
+            index += 1 + dir_rows.row_offset_list[position];
+            match fs_obj {
+                FSObj::DirRef(dir_ref) if self.dir_hash.contains_key(&dir_ref.path) => {
+                    dir_path = entry_path;
+                }
+                _ => return (entry_path == path).then_some(index),
+            }
+        }
+
+        Some(index)
+    }
+
+    //
+    // The rows whose names match 'query', in order.
+    //
+    pub fn matching_rows(&mut self, matcher: &SkimMatcherV2, query: &str) -> &[usize] {
+        if !matches!(&self.match_cache, Some((match_query, _)) if match_query == query) {
+            let mut index_list = Vec::new();
+            if self.row_count() > 0 {
+                let root_path = self.root_path.clone();
+                if is_name_match(matcher, &root_path, query) {
+                    index_list.push(0);
+                }
+                self.collect_matching_rows(&root_path, 0, matcher, query, &mut index_list);
+            }
+            self.match_cache = Some((query.to_string(), index_list));
+        }
+
+        match &self.match_cache {
+            Some((_, index_list)) => index_list,
+            None => &[],
+        }
+    }
+
+    fn collect_matching_rows(
+        &self,
+        dir_path: &Path,
+        dir_index: usize,
+        matcher: &SkimMatcherV2,
+        query: &str,
+        index_list: &mut Vec<usize>,
+    ) {
+        let (dir_rows, dir) = match (self.rows_hash.get(dir_path), self.dir_hash.get(dir_path)) {
+            (Some(dir_rows), Some(dir)) => (dir_rows, dir),
+            _ => return,
+        };
+        for (entry_rows, row_offset) in dir_rows
+            .entry_rows_list
+            .iter()
+            .zip(&dir_rows.row_offset_list)
+        {
+            let index = dir_index + 1 + row_offset;
+            let fs_obj = &dir.dir_obj_list[entry_rows.entry_index];
+            if is_name_match(matcher, fs_obj_path(fs_obj), query) {
+                index_list.push(index);
+            }
+            if let FSObj::DirRef(dir_ref) = fs_obj {
+                self.collect_matching_rows(&dir_ref.path, index, matcher, query, index_list);
+            }
+        }
+    }
+
+    //
+    // The dir the row at 'index' is in, the entry in it the row shows (or None for the
+    // dir's own row), and the row's level.
+    //
+    fn locate(&mut self, index: usize) -> Option<(PathBuf, Option<usize>, u32)> {
+        if index >= self.row_count() {
+            return None;
+        }
+        let mut dir_path = self.root_path.clone();
+        let mut index = index;
+        let mut level = 1;
+        while index > 0 {
+            // Counted, along with everything under it, by row_count().
+            let dir_rows = self.rows_hash.get(&dir_path)?;
+            let position = dir_rows
+                .row_offset_list
+                .partition_point(|row_offset| *row_offset < index)
+                - 1;
+            index -= 1 + dir_rows.row_offset_list[position];
+            let entry_index = dir_rows.entry_rows_list[position].entry_index;
+            match &self.dir_hash.get(&dir_path)?.dir_obj_list[entry_index] {
+                FSObj::DirRef(dir_ref) if self.dir_hash.contains_key(&dir_ref.path) => {
+                    dir_path = dir_ref.path.clone();
+                    level += 1;
+                }
+                _ => return Some((dir_path, Some(entry_index), level + 1)),
+            }
+        }
+
+        Some((dir_path, None, level))
+    }
+
+    fn clear_rows(&mut self) {
+        self.rows_hash.clear();
+        self.changed_entry_hash.clear();
+    }
+
+    //
+    // 'path' has changed, so its place in the dir it's in, and that dir's in the one it's
+    // in, and so on up, may have. Worked out again by dir_row_count().
+    //
+    fn note_changed(&mut self, path: &Path) {
+        let mut entry_path = path;
+        while let Some(dir_path) = entry_path.parent().filter(|_| entry_path != self.root_path) {
+            self.changed_entry_hash
+                .entry(dir_path.to_path_buf())
+                .or_default()
+                .insert(entry_path.to_path_buf());
+            entry_path = dir_path;
+        }
+    }
+
+    fn dir_row_count(&mut self, path: &Path) -> usize {
+        let changed_entry_set = self.changed_entry_hash.remove(path);
+        let mut dir_rows = match self.rows_hash.remove(path) {
+            Some(dir_rows) => dir_rows,
+            None => return self.build_dir_rows(path),
+        };
+        if let Some(changed_entry_set) = changed_entry_set {
+            let dir = match self.dir_hash.get(path) {
+                Some(dir) => dir,
+                None => return 0,
+            };
+            if self.is_open(dir) != dir_rows.is_open {
+                return self.build_dir_rows(path);
+            }
+            // Each changed entry comes out, and goes back in where it now belongs.
+            let changed_index_set: HashSet<usize> = changed_entry_set
+                .iter()
+                .filter_map(|entry_path| dir_rows.entry_index_hash.get(entry_path).copied())
+                .collect();
+            dir_rows
+                .entry_rows_list
+                .retain(|entry_rows| !changed_index_set.contains(&entry_rows.entry_index));
+            for entry_rows in self.entry_rows_list(path, changed_index_set.into_iter()) {
+                let position = dir_rows
+                    .entry_rows_list
+                    .partition_point(|other| other.sort_key() < entry_rows.sort_key());
+                dir_rows.entry_rows_list.insert(position, entry_rows);
+            }
+            set_row_offsets(&mut dir_rows);
+        }
+        let row_count = dir_rows.row_count;
+        self.rows_hash.insert(path.to_path_buf(), dir_rows);
+
+        row_count
+    }
+
+    fn build_dir_rows(&mut self, path: &Path) -> usize {
+        let dir = match self.dir_hash.get(path) {
+            Some(dir) => dir,
+            None => return 0,
+        };
+        let is_open = self.is_open(dir);
+        let mut entry_index_hash = HashMap::new();
+        let mut entry_rows_list = Vec::new();
+        if is_open {
+            entry_index_hash = dir
+                .dir_obj_list
+                .iter()
+                .enumerate()
+                .map(|(entry_index, fs_obj)| (fs_obj_path(fs_obj).to_path_buf(), entry_index))
+                .collect();
+            let entry_count = dir.dir_obj_list.len();
+            entry_rows_list = self.entry_rows_list(path, 0..entry_count);
+            entry_rows_list.sort_by_key(EntryRows::sort_key);
+        }
+        let mut dir_rows = DirRows {
+            is_open,
+            entry_rows_list,
+            row_offset_list: Vec::new(),
+            row_count: 1,
+            entry_index_hash,
+        };
+        set_row_offsets(&mut dir_rows);
+        let row_count = dir_rows.row_count;
+        self.rows_hash.insert(path.to_path_buf(), dir_rows);
+
+        row_count
+    }
+
+    //
+    // The rows of those of 'path's entries at 'entry_index_iter' that are shown.
+    //
+    fn entry_rows_list(
+        &mut self,
+        path: &Path,
+        entry_index_iter: impl Iterator<Item = usize>,
+    ) -> Vec<EntryRows> {
+        let dir = match self.dir_hash.get(path) {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        // Unscanned sub dirs are a row of their own, like files.
+        let entry_list: Vec<(usize, u64, Option<PathBuf>)> = entry_index_iter
+            .filter_map(|entry_index| {
+                let fs_obj = dir.dir_obj_list.get(entry_index)?;
+                if !self.is_shown(fs_obj_path(fs_obj)) {
+                    return None;
+                }
+                let sub_dir_path = match fs_obj {
+                    FSObj::DirRef(dir_ref) if self.dir_hash.contains_key(&dir_ref.path) => {
+                        Some(dir_ref.path.clone())
+                    }
+                    _ => None,
+                };
+                Some((entry_index, self.entry_size(fs_obj), sub_dir_path))
+            })
+            .collect();
+
+        entry_list
+            .into_iter()
+            .map(|(entry_index, size_in_bytes, sub_dir_path)| EntryRows {
+                entry_index,
+                size_in_bytes,
+                row_count: match sub_dir_path {
+                    Some(sub_dir_path) => self.dir_row_count(&sub_dir_path),
+                    None => 1,
+                },
+            })
+            .collect()
+    }
+
+    fn is_open(&self, dir: &Dir) -> bool {
+        match &self.filter_path_set {
+            Some(_) if dir.path == self.root_path => true,
+            Some(filter_path_set) => dir
+                .dir_obj_list
+                .iter()
+                .any(|fs_obj| filter_path_set.contains(fs_obj_path(fs_obj))),
+            None => dir.is_open,
+        }
+    }
+
+    fn entry_size(&self, fs_obj: &FSObj) -> u64 {
+        match fs_obj {
+            // A DirRef's size is its dir's.
+            FSObj::DirRef(dir_ref) => self
+                .dir_hash
+                .get(&dir_ref.path)
+                .map_or(0, |dir| dir.size_in_bytes_for(self.size_mode)),
+            fs_obj => fs_obj.size_in_bytes_for(self.size_mode),
+        }
+    }
+
+    fn is_shown(&self, path: &Path) -> bool {
+        match &self.filter_path_set {
+            Some(filter_path_set) => filter_path_set.contains(path),
+            None => true,
+        }
+    }
+}
+
+fn set_row_offsets(dir_rows: &mut DirRows) {
+    dir_rows.row_offset_list.clear();
+    dir_rows.row_count = 1;
+    for entry_rows in &dir_rows.entry_rows_list {
+        dir_rows.row_offset_list.push(dir_rows.row_count - 1);
+        dir_rows.row_count += entry_rows.row_count;
+    }
+}
+
+//
+// The name a row shows for 'path': its file name, or the whole path if it has none (the
+// root '/', say).
+//
+fn name_of(path: &Path) -> String {
+    file_name(path).unwrap_or_else(|| path.display().to_string())
+}
+
+fn percent_of(size_in_bytes: u64, total_bytes: f64) -> String {
+    format!("{}%", ((size_in_bytes as f64 / total_bytes) * 100.0) as u8)
+}
+
+fn dir_row(
+    dir: &Dir,
+    is_open: bool,
+    level: u32,
+    size_mode: SizeMode,
+    total_bytes: f64,
+) -> IntermediateState {
+    let flipper = match is_open {
+        true => "⏷",
+        false => "⏵",
+    };
+    let name = name_of(&dir.path);
+    let mut name = format!("{}{} {}", indent_prefix_for_level(level), flipper, name);
+    let has_error = !dir.error_list.is_empty();
+    if has_error {
+        // Some of it couldn't be read, so its size may be short.
+        name = format!("{} (!)", name);
+    }
+    let size_in_bytes = dir.size_in_bytes_for(size_mode);
+
+    IntermediateState {
+        ui_row: vec![
+            name,
+            percent_of(size_in_bytes, total_bytes),
+            human_readable_bytes(size_in_bytes),
+        ],
+        is_marked: dir.is_marked,
+        is_excluded: dir.is_excluded,
+        has_error,
+    }
+}
+
+fn entry_row(
+    fs_obj: &FSObj,
+    level: u32,
+    size_mode: SizeMode,
+    total_bytes: f64,
+) -> IntermediateState {
+    match fs_obj {
+        FSObj::Dir(dir) => dir_row(dir, dir.is_open, level, size_mode, total_bytes),
+        FSObj::DirRef(dir_ref) => {
+            // Not scanned yet.
+            let name = name_of(&dir_ref.path);
+            let name = format!("{}> {}", indent_prefix_for_level(level), name);
+            let size_in_bytes = dir_ref.size_in_bytes_for(size_mode);
+
+            IntermediateState {
+                ui_row: vec![
+                    name,
+                    percent_of(size_in_bytes, total_bytes),
+                    human_readable_bytes(size_in_bytes),
+                ],
+                is_marked: dir_ref.is_marked,
+                is_excluded: dir_ref.is_excluded,
+                has_error: false,
+            }
+        }
+        FSObj::File(file) => {
+            let name = name_of(&file.path);
+            let mut name = format!("{}  {}", indent_prefix_for_level(level), name);
+            if file.nlink > 1 {
+                // Hard linked. Its size may be counted under another of its paths.
+                name = format!("{} ({} links)", name, file.nlink);
+            }
+            let size_in_bytes = file.size_in_bytes_for(size_mode);
+
+            IntermediateState {
+                ui_row: vec![
+                    name,
+                    percent_of(size_in_bytes, total_bytes),
+                    human_readable_bytes(size_in_bytes),
+                ],
+                is_marked: file.is_marked,
+                is_excluded: file.is_excluded,
+                has_error: false,
+            }
+        }
+        FSObj::MountPoint(mount_point) => {
+            let name = name_of(&mount_point.path);
+            let name = format!("{}  {} (mount point)", indent_prefix_for_level(level), name);
+
+            IntermediateState {
+                ui_row: vec![name, "".to_string(), "".to_string()],
+                is_marked: false,
+                is_excluded: false,
+                has_error: false,
+            }
+        }
+        FSObj::SymLink(sym_link) => {
+            let name = name_of(&sym_link.path);
+            let name = format!("{}  {}", indent_prefix_for_level(level), name);
+            let size_in_bytes = sym_link.size_in_bytes_for(size_mode);
+
+            IntermediateState {
+                ui_row: vec![
+                    name,
+                    percent_of(size_in_bytes, total_bytes),
+                    human_readable_bytes(size_in_bytes),
+                ],
+                is_marked: sym_link.is_marked,
+                is_excluded: sym_link.is_excluded,
+                has_error: false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv_update(dirp_state: &DirpState, tree_view: &mut TreeView) -> bool {
+        // Applies the next update, and returns whether the scan is complete.
+        loop {
+            if let UserMessage::StateUpdate(state_update) = dirp_state.recv() {
+                tree_view.apply(
+                    state_update.dir_change_list,
+                    state_update.filter_change,
+                    state_update.size_mode,
+                );
+                return state_update.scan_progress.is_complete;
+            }
+        }
+    }
+
+    fn row_path_list(tree_view: &mut TreeView) -> Vec<PathBuf> {
+        let row_count = tree_view.row_count();
+        (0..row_count)
+            .filter_map(|index| tree_view.path_at(index))
+            .collect()
+    }
+
+    fn scanned_tree_view(open_dir_path: Option<&str>) -> TreeView {
+        // A tree view of the whole of ./test, with 'open_dir_path' open as well as the root.
+        let dirp_state = DirpState::new(
+            PathBuf::from("./test"),
+            SizeMode::Apparent,
+            ScanSource::Disk,
+            ScanOptions::default(),
+        );
+        let mut tree_view = TreeView::new(PathBuf::from("./test"), SizeMode::Apparent);
+        while !recv_update(&dirp_state, &mut tree_view) {}
+        if let Some(open_dir_path) = open_dir_path {
+            dirp_state.send(DirpStateMessage::OpenDir(PathBuf::from(open_dir_path)));
+            recv_update(&dirp_state, &mut tree_view);
+        }
+        dirp_state.quit();
+
+        tree_view
+    }
+
+    fn grow_dir(tree_view: &mut TreeView, path: &Path, size_in_bytes: u64) {
+        // As an update from the state thread would.
+        let dir = tree_view.dir_hash.get(path).unwrap();
+        let dir_update = DirUpdate {
+            path: dir.path.clone(),
+            size_in_bytes,
+            disk_size_in_bytes: dir.disk_size_in_bytes,
+            is_open: dir.is_open,
+            is_marked: dir.is_marked,
+            entry_list: Vec::new(),
+        };
+        tree_view.apply(
+            vec![DirChange::Updated(dir_update)],
+            None,
+            SizeMode::Apparent,
+        );
+    }
+
+    #[test]
+    fn test_tree_view_rows() {
+        // The root, then its entries biggest first.
+        let mut tree_view = scanned_tree_view(None);
+        let path_list = row_path_list(&mut tree_view);
+        assert_eq!(path_list.len(), 6, "Error: Unexpected result.");
+        assert_eq!(
+            path_list[0],
+            PathBuf::from("./test"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            path_list[1],
+            PathBuf::from("./test/a"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(
+            path_list[5],
+            PathBuf::from("./test/0.txt.lnk"),
+            "Error: Unexpected result."
+        );
+
+        // An open dir's rows come straight after its own, and each row is found again by
+        // its path.
+        let mut tree_view = scanned_tree_view(Some("./test/c"));
+        let path_list = row_path_list(&mut tree_view);
+        assert_eq!(path_list.len(), 8, "Error: Unexpected result.");
+        let c_index = tree_view.index_of(Path::new("./test/c")).unwrap();
+        let mut c_entry_list = path_list[c_index + 1..c_index + 3].to_vec();
+        c_entry_list.sort();
+        assert_eq!(
+            c_entry_list,
+            vec![PathBuf::from("./test/c/4.txt"), PathBuf::from("./test/c/d")],
+            "Error: Unexpected result."
+        );
+        for (index, path) in path_list.iter().enumerate() {
+            assert_eq!(
+                tree_view.index_of(path),
+                Some(index),
+                "Error: Unexpected result."
+            );
+            assert_eq!(
+                tree_view.path_at(index).as_ref(),
+                Some(path),
+                "Error: Unexpected result."
+            );
+        }
+        assert_eq!(
+            tree_view.index_of(Path::new("./test/a/1.txt")),
+            None,
+            "Error: Unexpected result."
+        );
+        assert_eq!(tree_view.path_at(8), None, "Error: Unexpected result.");
+    }
+
+    #[test]
+    fn test_tree_view_resort() {
+        // A dir that grows moves up, and only it, and the dirs it's in, move.
+        let mut tree_view = scanned_tree_view(Some("./test/c"));
+        grow_dir(&mut tree_view, Path::new("./test/e"), 1_000_000);
+        assert!(
+            tree_view
+                .changed_entry_hash
+                .contains_key(Path::new("./test")),
+            "Error: Unexpected result."
+        );
+        let path_list = row_path_list(&mut tree_view);
+        assert_eq!(
+            path_list[1],
+            PathBuf::from("./test/e"),
+            "Error: Unexpected result."
+        );
+        assert_eq!(path_list.len(), 8, "Error: Unexpected result.");
+        for (index, path) in path_list.iter().enumerate() {
+            assert_eq!(
+                tree_view.index_of(path),
+                Some(index),
+                "Error: Unexpected result."
+            );
+        }
+    }
+
+    #[test]
+    fn test_tree_view_search() {
+        // The rows that match a search, in order.
+        let mut tree_view = scanned_tree_view(Some("./test/c"));
+        let matcher = SkimMatcherV2::default();
+        let matching_index_list = |tree_view: &mut TreeView| {
+            let path_list = row_path_list(tree_view);
+            (0..path_list.len())
+                .filter(|index| is_name_match(&matcher, &path_list[*index], "txt"))
+                .collect::<Vec<usize>>()
+        };
+        let index_list = tree_view.matching_rows(&matcher, "txt").to_vec();
+        assert!(!index_list.is_empty(), "Error: Unexpected result.");
+        assert_eq!(
+            index_list,
+            matching_index_list(&mut tree_view),
+            "Error: Unexpected result."
+        );
+
+        // Kept for the same query, until the rows change.
+        assert!(
+            matches!(&tree_view.match_cache, Some((query, _)) if query == "txt"),
+            "Error: Unexpected result."
+        );
+        grow_dir(&mut tree_view, Path::new("./test/e"), 1_000_000);
+        assert!(tree_view.match_cache.is_none(), "Error: Unexpected result.");
+        let index_list = tree_view.matching_rows(&matcher, "txt").to_vec();
+        assert_eq!(
+            index_list,
+            matching_index_list(&mut tree_view),
+            "Error: Unexpected result."
+        );
+    }
+
+    #[test]
+    fn test_tree_view_filter() {
+        // A filter opens the dirs on the way to what it leaves shown.
+        let mut tree_view = scanned_tree_view(None);
+        let filter_path_list = vec![
+            PathBuf::from("./test"),
+            PathBuf::from("./test/c"),
+            PathBuf::from("./test/c/d"),
+            PathBuf::from("./test/c/d/5.txt"),
+        ];
+        tree_view.apply(
+            Vec::new(),
+            Some(FilterChange::On(filter_path_list.clone())),
+            SizeMode::Apparent,
+        );
+        assert_eq!(
+            row_path_list(&mut tree_view),
+            filter_path_list,
+            "Error: Unexpected result."
+        );
+
+        // And turning it off shows what was open before.
+        tree_view.apply(Vec::new(), Some(FilterChange::Off), SizeMode::Apparent);
+        assert_eq!(
+            row_path_list(&mut tree_view).len(),
+            6,
+            "Error: Unexpected result."
+        );
+    }
+}
@@ -1,9 +1,11 @@
+use crate::types::{MarkedSummary, RemoveMode, ScanError, ScanProgress, SizeMode};
+use crate::utils::human_readable_bytes;
 use std::io;
 use tui::{
     backend::Backend,
     layout::{Constraint, Layout},
     style::{Color, Modifier, Style},
-    widgets::{Block, Borders, Cell, Row, Table, TableState},
+    widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState},
     Frame, Terminal,
 };
 
@@ -12,6 +14,18 @@ use tui::{
 
 pub struct App<'a> {
     pub path: String,
+    pub size_mode: SizeMode,
+    pub remove_mode: RemoveMode,
+    pub marked_summary: MarkedSummary,
+    pub scan_progress: ScanProgress,
+    pub status_message: Option<String>,
+    // Only as many as the error panel lists, of 'scan_error_count'.
+    pub scan_error_list: Vec<ScanError>,
+    pub scan_error_count: usize,
+    pub is_error_panel_open: bool,
+    // Shown in place of the progress line while a query is typed.
+    pub query_line: Option<String>,
+    pub filter: Option<String>,
     state: TableState,
     items: Vec<AppRow<'a>>,
 }
@@ -19,52 +33,33 @@ pub struct App<'a> {
 pub struct AppRow<'a> {
     pub display_data: Vec<&'a str>,
     pub is_marked: bool,
+    pub is_excluded: bool,
+    pub has_error: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(path: String, items: Vec<AppRow<'a>>) -> App<'a> {
+    pub fn new(path: String, size_mode: SizeMode, items: Vec<AppRow<'a>>) -> App<'a> {
         App {
             path,
+            size_mode,
+            remove_mode: RemoveMode::Trash,
+            marked_summary: MarkedSummary::default(),
+            scan_progress: ScanProgress::default(),
+            status_message: None,
+            scan_error_list: Vec::new(),
+            scan_error_count: 0,
+            is_error_panel_open: false,
+            query_line: None,
+            filter: None,
             state: TableState::default(),
             items,
         }
     }
 
-    pub fn selected(&mut self) -> usize {
-        self.state.selected().expect("We always want a selection.")
-    }
-
+    // 'state' is an index into 'items', which only hold the rows on screen.
     pub fn set_selected(&mut self, state: usize) {
         self.state.select(Some(state));
     }
-
-    pub fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    i
-                } else {
-                    i + 1
-                }
-            }
-            None => 0,
-        };
-        self.state.select(Some(i));
-    }
-
-    pub fn previous(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    i
-                } else {
-                    i - 1
-                }
-            }
-            None => 0,
-        };
-        self.state.select(Some(i));
-    }
 }
 
 pub fn step_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<()> {
@@ -72,9 +67,37 @@ pub fn step_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Res
     Ok(())
 }
 
+// The most errors the error panel lists before summing up the rest.
+pub const MAX_ERROR_PANEL_LINES: usize = 8;
+
+fn error_panel_height(scan_error_count: usize, is_error_panel_open: bool) -> u16 {
+    match is_error_panel_open {
+        true => scan_error_count.clamp(1, MAX_ERROR_PANEL_LINES) as u16 + 2,
+        false => 0,
+    }
+}
+
+//
+// How many rows the table has room for, on a terminal 'height' lines high. Less the
+// error panel, the progress line, the table's borders, and its header.
+//
+pub fn table_row_count(height: u16, scan_error_count: usize, is_error_panel_open: bool) -> usize {
+    height
+        .saturating_sub(error_panel_height(scan_error_count, is_error_panel_open) + 4)
+        .max(1) as usize
+}
+
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let error_panel_height = error_panel_height(app.scan_error_count, app.is_error_panel_open);
     let rects = Layout::default()
-        .constraints([Constraint::Percentage(100)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(error_panel_height),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         // .margin(5)
         .split(f.size());
 
@@ -82,9 +105,15 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let normal_header_style = Style::default().bg(Color::Blue);
     let normal_style = Style::default();
     let disabled_style = Style::default().add_modifier(Modifier::DIM);
-    let header_cells = ["", "%", "Size"]
-        .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::LightGreen)));
+    let excluded_style = Style::default().fg(Color::DarkGray);
+    let error_style = Style::default().fg(Color::LightRed);
+    let size_header = match app.size_mode {
+        SizeMode::Apparent => "Size",
+        SizeMode::Disk => "Disk Usage",
+    };
+    let header_cells = ["", "%", size_header]
+        .into_iter()
+        .map(|h| Cell::from(h).style(Style::default().fg(Color::LightGreen)));
     let header = Row::new(header_cells)
         .style(normal_header_style)
         .height(1)
@@ -100,6 +129,10 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         let cells = item.display_data.iter().map(|c| {
             if item.is_marked {
                 Cell::from(*c).style(disabled_style)
+            } else if item.is_excluded {
+                Cell::from(*c).style(excluded_style)
+            } else if item.has_error {
+                Cell::from(*c).style(error_style)
             } else {
                 Cell::from(*c).style(normal_style)
             }
@@ -107,8 +140,20 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         Row::new(cells).height(height as u16).bottom_margin(0)
     });
 
-    let path = app.path.clone();
-    let path = format!(" {} ", path);
+    let path = match app.remove_mode {
+        RemoveMode::Trash => app.path.clone(),
+        RemoveMode::Permanent => format!("{} (permanent delete)", app.path),
+    };
+    let path = if app.marked_summary.size_in_bytes > 0 {
+        format!(
+            " {} - Marked: {}, Frees: {} ",
+            path,
+            human_readable_bytes(app.marked_summary.size_in_bytes),
+            human_readable_bytes(app.marked_summary.reclaimable_size_in_bytes)
+        )
+    } else {
+        format!(" {} ", path)
+    };
 
     let t = Table::new(rows)
         .header(header)
@@ -121,4 +166,67 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
             Constraint::Min(10),
         ]);
     f.render_stateful_widget(t, rects[0], &mut app.state);
+
+    if app.is_error_panel_open {
+        let mut error_line_list: Vec<String> = app
+            .scan_error_list
+            .iter()
+            .take(MAX_ERROR_PANEL_LINES)
+            .map(|error| format!(" {}: {}", error.path.display(), error.message))
+            .collect();
+        if app.scan_error_count > MAX_ERROR_PANEL_LINES {
+            // Make room to say how many more there are.
+            error_line_list.pop();
+            error_line_list.push(format!(
+                " ...and {} more",
+                app.scan_error_count - error_line_list.len()
+            ));
+        }
+        if error_line_list.is_empty() {
+            error_line_list.push(" Everything was read.".to_string());
+        }
+        f.render_widget(
+            Paragraph::new(error_line_list.join("\n"))
+                .style(error_style)
+                .block(Block::default().borders(Borders::ALL).title(" Errors ")),
+            rects[1],
+        );
+    }
+
+    let progress = &app.scan_progress;
+    let mut progress_line = format!(
+        " {}: {} dirs, {} files, {}, {:.1}s",
+        match (progress.is_complete, progress.pending_scan_count) {
+            (false, _) => "Scanning",
+            (true, 0) => "Scan complete",
+            (true, _) => "Rescanning",
+        },
+        progress.dirs_scanned,
+        progress.files_scanned,
+        human_readable_bytes(progress.size_in_bytes),
+        progress.elapsed.as_secs_f64()
+    );
+    if app.scan_error_count > 0 {
+        progress_line = format!(
+            "{}, {} unreadable ('E' lists them)",
+            progress_line, app.scan_error_count
+        );
+    }
+    if let Some(filter) = &app.filter {
+        progress_line = format!("{} - Showing matches for '{}'", progress_line, filter);
+    }
+    if let Some(status_message) = &app.status_message {
+        progress_line = format!("{} - {}", progress_line, status_message);
+    }
+    let progress_style = match progress.is_complete {
+        true => Style::default().fg(Color::LightGreen),
+        false => Style::default().fg(Color::Yellow),
+    };
+    if let Some(query_line) = &app.query_line {
+        progress_line = format!(" {}_", query_line);
+    }
+    f.render_widget(
+        Paragraph::new(progress_line).style(progress_style),
+        rects[2],
+    );
 }
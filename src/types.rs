@@ -1,14 +1,23 @@
 use crate::dirp_state::dirp_state_thread_spawn;
+use crate::exclude::ExcludeRules;
+use crate::scan_cache::ScanCache;
+use crate::utils::fs_obj_path;
+use log::LevelFilter;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Values, HashMap, HashSet},
+    fmt,
     hash::Hash,
-    path::PathBuf,
-    sync::mpsc::{channel, Receiver, Sender},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
     thread::JoinHandle,
+    time::Duration,
 };
 
 pub type FSObjList = Vec<FSObj>;
-pub type DirHash = HashMap<String, Dir>;
+pub type InodeHash = HashMap<(u64, u64), PathBuf>;
 
 #[derive(Debug, Clone, Hash)]
 pub enum FSObj {
@@ -16,45 +25,279 @@ pub enum FSObj {
     SymLink(SymLink),
     Dir(Dir),
     DirRef(DirRef),
+    MountPoint(MountPoint),
 }
 
 #[derive(Debug, Clone, Hash)]
 pub struct File {
-    pub path: String,
+    pub path: PathBuf,
     pub size_in_bytes: u64,
+    pub disk_size_in_bytes: u64,
     pub percent: u8,
     pub is_marked: bool,
+    pub is_excluded: bool,
+    // Seconds since the epoch.
+    pub mtime: i64,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
 }
 
 #[derive(Debug, Clone, Hash)]
 pub struct SymLink {
-    pub path: String,
+    pub path: PathBuf,
     pub size_in_bytes: u64,
+    pub disk_size_in_bytes: u64,
     pub percent: u8,
     pub is_marked: bool,
+    pub is_excluded: bool,
+    pub mtime: i64,
 }
 
 #[derive(Debug, Clone, Hash)]
 pub struct Dir {
-    pub path: String,
+    pub path: PathBuf,
     pub size_in_bytes: u64,
+    pub disk_size_in_bytes: u64,
     pub percent: u8,
     pub is_marked: bool,
+    pub is_excluded: bool,
+    pub mtime: i64,
     pub is_open: bool,
     pub dir_obj_list: FSObjList,
+    // What couldn't be read while listing this dir: the dir itself, or entries in it.
+    pub error_list: Vec<ScanError>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub message: String,
 }
 
 #[derive(Debug, Clone, Hash)]
 pub struct DirRef {
-    pub path: String,
+    pub path: PathBuf,
     pub is_open: bool,
     pub size_in_bytes: u64,
+    pub disk_size_in_bytes: u64,
     pub percent: u8,
     pub is_marked: bool,
+    pub is_excluded: bool,
+    pub mtime: i64,
+}
+
+//
+// Every dir, by path. A sub dir is a DirRef in its parent's dir_obj_list, and has its
+// own entry here. Each path that is inserted, removed, or borrowed mutably is noted, so
+// just what has changed can be passed on (see take_dir_change_list()).
+//
+#[derive(Debug, Default)]
+pub struct DirHash {
+    dir_hash: HashMap<PathBuf, Dir>,
+    changed_path_set: HashSet<PathBuf>,
+    // Of those, the ones inserted, so all of them is new.
+    listed_path_set: HashSet<PathBuf>,
+    // The entries changed through get_entry_mut(), by the path of the dir they're in.
+    changed_entry_hash: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl DirHash {
+    pub fn new() -> DirHash {
+        DirHash::default()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Dir> {
+        self.dir_hash.get(path)
+    }
+
+    // Only what a DirUpdate carries (its sizes, is_open and is_marked) is passed on from
+    // a change made through this. Its entries are changed with get_entry_mut().
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut Dir> {
+        let dir = self.dir_hash.get_mut(path)?;
+        self.changed_path_set.insert(path.to_path_buf());
+        Some(dir)
+    }
+
+    pub fn get_entry_mut(&mut self, dir_path: &Path, path: &Path) -> Option<&mut FSObj> {
+        let fs_obj = self
+            .dir_hash
+            .get_mut(dir_path)?
+            .dir_obj_list
+            .iter_mut()
+            .find(|fs_obj| fs_obj_path(fs_obj) == path)?;
+        self.changed_path_set.insert(dir_path.to_path_buf());
+        self.changed_entry_hash
+            .entry(dir_path.to_path_buf())
+            .or_default()
+            .insert(path.to_path_buf());
+        Some(fs_obj)
+    }
+
+    pub fn contains_key(&self, path: &Path) -> bool {
+        self.dir_hash.contains_key(path)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, dir: Dir) -> Option<Dir> {
+        self.changed_path_set.insert(path.clone());
+        self.listed_path_set.insert(path.clone());
+        self.dir_hash.insert(path, dir)
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<Dir> {
+        let dir = self.dir_hash.remove(path)?;
+        self.changed_path_set.insert(path.to_path_buf());
+        Some(dir)
+    }
+
+    pub fn values(&self) -> Values<'_, PathBuf, Dir> {
+        self.dir_hash.values()
+    }
+
+    //
+    // The paths that have changed since the last call (of this, or
+    // take_dir_change_list()). Those no longer in the hash have been removed.
+    //
+    pub fn take_changed_path_set(&mut self) -> HashSet<PathBuf> {
+        self.listed_path_set.clear();
+        self.changed_entry_hash.clear();
+        std::mem::take(&mut self.changed_path_set)
+    }
+
+    //
+    // What has changed since the last call (of this, or take_changed_path_set()), for
+    // apply() to bring a copy of the hash up to date with. A dir that was only resized,
+    // or opened, or marked, goes as a DirUpdate, without the entries that didn't change.
+    //
+    pub fn take_dir_change_list(&mut self) -> Vec<DirChange> {
+        let listed_path_set = std::mem::take(&mut self.listed_path_set);
+        let mut changed_entry_hash = std::mem::take(&mut self.changed_entry_hash);
+        let mut dir_change_list = Vec::new();
+        for path in std::mem::take(&mut self.changed_path_set) {
+            let dir = match self.dir_hash.get(&path) {
+                Some(dir) => dir,
+                None => {
+                    dir_change_list.push(DirChange::Removed(path));
+                    continue;
+                }
+            };
+            if listed_path_set.contains(&path) {
+                dir_change_list.push(DirChange::Listed(dir.clone()));
+                continue;
+            }
+            let entry_list = match changed_entry_hash.remove(&path) {
+                Some(entry_path_set) => dir
+                    .dir_obj_list
+                    .iter()
+                    .filter(|fs_obj| entry_path_set.contains(fs_obj_path(fs_obj)))
+                    .cloned()
+                    .collect(),
+                None => Vec::new(),
+            };
+            dir_change_list.push(DirChange::Updated(DirUpdate {
+                path,
+                size_in_bytes: dir.size_in_bytes,
+                disk_size_in_bytes: dir.disk_size_in_bytes,
+                is_open: dir.is_open,
+                is_marked: dir.is_marked,
+                entry_list,
+            }));
+        }
+
+        dir_change_list
+    }
+
+    pub fn apply(&mut self, dir_change: DirChange) {
+        match dir_change {
+            DirChange::Listed(dir) => {
+                self.insert(dir.path.clone(), dir);
+            }
+            DirChange::Updated(dir_update) => {
+                let dir = match self.get_mut(&dir_update.path) {
+                    Some(dir) => dir,
+                    None => return,
+                };
+                dir.size_in_bytes = dir_update.size_in_bytes;
+                dir.disk_size_in_bytes = dir_update.disk_size_in_bytes;
+                dir.is_open = dir_update.is_open;
+                dir.is_marked = dir_update.is_marked;
+                if dir_update.entry_list.is_empty() {
+                    return;
+                }
+                let mut entry_hash: HashMap<PathBuf, FSObj> = dir_update
+                    .entry_list
+                    .into_iter()
+                    .map(|fs_obj| (fs_obj_path(&fs_obj).to_path_buf(), fs_obj))
+                    .collect();
+                for fs_obj in &mut dir.dir_obj_list {
+                    if let Some(entry) = entry_hash.remove(fs_obj_path(fs_obj)) {
+                        *fs_obj = entry;
+                    }
+                }
+            }
+            DirChange::Removed(path) => {
+                self.remove(&path);
+            }
+        }
+    }
+}
+
+//
+// How sizes are reported. 'Apparent' is the length of a file's contents. 'Disk' is the
+// space actually allocated for it (st_blocks * 512, like du), which is smaller for
+// sparse files and larger for lots of tiny ones. Both are always collected, so the
+// mode can be switched without a rescan.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SizeMode {
+    Apparent,
+    Disk,
+}
+
+impl SizeMode {
+    pub fn toggled(self) -> SizeMode {
+        match self {
+            SizeMode::Apparent => SizeMode::Disk,
+            SizeMode::Disk => SizeMode::Apparent,
+        }
+    }
+}
+
+//
+// What 'x' does with marked paths. Moving them to the Trash by default, or removing
+// them for good, where there's no usable Trash (or the point is to free the space).
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemoveMode {
+    Trash,
+    Permanent,
+}
+
+impl RemoveMode {
+    pub fn toggled(self) -> RemoveMode {
+        match self {
+            RemoveMode::Trash => RemoveMode::Permanent,
+            RemoveMode::Permanent => RemoveMode::Trash,
+        }
+    }
+}
+
+// A dir on another file system, which the scan doesn't cross into.
+#[derive(Debug, Clone, Hash)]
+pub struct MountPoint {
+    pub path: PathBuf,
 }
 
 pub trait SizeInBytes {
     fn size_in_bytes(&self) -> u64;
+    fn disk_size_in_bytes(&self) -> u64;
+
+    fn size_in_bytes_for(&self, size_mode: SizeMode) -> u64 {
+        match size_mode {
+            SizeMode::Apparent => self.size_in_bytes(),
+            SizeMode::Disk => self.disk_size_in_bytes(),
+        }
+    }
 }
 
 impl SizeInBytes for FSObj {
@@ -64,28 +307,99 @@ impl SizeInBytes for FSObj {
             FSObj::DirRef(dir_ref) => dir_ref.size_in_bytes,
             FSObj::File(file) => file.size_in_bytes,
             FSObj::SymLink(sym_link) => sym_link.size_in_bytes,
+            FSObj::MountPoint(_) => 0,
+        }
+    }
+
+    fn disk_size_in_bytes(&self) -> u64 {
+        match self {
+            FSObj::Dir(dir) => dir.disk_size_in_bytes,
+            FSObj::DirRef(dir_ref) => dir_ref.disk_size_in_bytes,
+            FSObj::File(file) => file.disk_size_in_bytes,
+            FSObj::SymLink(sym_link) => sym_link.disk_size_in_bytes,
+            FSObj::MountPoint(_) => 0,
         }
     }
 }
 
+impl SizeInBytes for Dir {
+    fn size_in_bytes(&self) -> u64 {
+        self.size_in_bytes
+    }
+
+    fn disk_size_in_bytes(&self) -> u64 {
+        self.disk_size_in_bytes
+    }
+}
+
+impl SizeInBytes for DirRef {
+    fn size_in_bytes(&self) -> u64 {
+        self.size_in_bytes
+    }
+
+    fn disk_size_in_bytes(&self) -> u64 {
+        self.disk_size_in_bytes
+    }
+}
+
+impl SizeInBytes for File {
+    fn size_in_bytes(&self) -> u64 {
+        self.size_in_bytes
+    }
+
+    fn disk_size_in_bytes(&self) -> u64 {
+        self.disk_size_in_bytes
+    }
+}
+
+impl SizeInBytes for SymLink {
+    fn size_in_bytes(&self) -> u64 {
+        self.size_in_bytes
+    }
+
+    fn disk_size_in_bytes(&self) -> u64 {
+        self.disk_size_in_bytes
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DirpStateMessage {
     DirScanMessage(Dir),
-    OpenDir(String),
-    CloseDir(String),
-    ToggleDir(String),
-    MarkPath(String),
-    UnmarkPath(String),
-    ToggleMarkPath(String),
-    RemoveMarked,
-    Timer,
+    OpenDir(PathBuf),
+    CloseDir(PathBuf),
+    ToggleDir(PathBuf),
+    MarkPath(PathBuf),
+    UnmarkPath(PathBuf),
+    ToggleMarkPath(PathBuf),
+    ToggleSizeMode,
+    // Show only what matches the query (and the dirs that lead to it), or everything.
+    SetFilter(Option<String>),
+    // Drop what is known about the dir at the path (or holding the file at the path),
+    // and everything under it, and scan it again.
+    Rescan(PathBuf),
+    PathCreated(PathBuf),
+    PathRemoved(PathBuf),
+    PathModified(PathBuf),
+    // Answered with a GetFullStateResponse once the scan is complete.
+    GetFullState,
+    RemoveMarked(RemoveMode),
+    // Writes what is marked to the file at the path, as a removal plan, and carries on.
+    SavePlan(PathBuf),
+    // A dry run of RemoveMarked. Writes what would be removed to the file at the path,
+    // or to stdout.
+    WritePlan(Option<PathBuf>),
+    // The state thread's own, for when changes have gathered long enough to send on.
+    Flush,
     #[allow(dead_code)]
     Quit,
 }
 
 #[derive(Debug, Hash)]
 pub enum UserMessage {
-    GetStateResponse(GetStateResponse),
+    // What has changed since the last one.
+    StateUpdate(StateUpdate),
+    // Every dir, open or not, with every size final.
+    GetFullStateResponse(GetStateResponse),
     Next,
     Previous,
     CloseDir,
@@ -94,23 +408,188 @@ pub enum UserMessage {
     MarkPath,
     UnmarkPath,
     ToggleMarkPath,
+    ToggleSizeMode,
+    Rescan,
+    Export,
+    ToggleRemoveMode,
+    SavePlan,
+    ToggleErrorPanel,
+    // Typing a query, to search ('/') for, or filter ('F') by.
+    StartSearch,
+    StartFilter,
+    QueryChar(char),
+    QueryBackspace,
+    // The next (or previous) row that matches the query.
+    QueryNext,
+    QueryPrevious,
+    QueryDone,
+    QueryCancel,
+    // Something for the status line.
+    Status(String),
     RemoveMarked,
     Quit,
 }
 
+//
+// What has changed in the state thread's DirHash since the last update. The UI keeps a
+// copy of it, which this brings up to date (see tree_view.rs). What couldn't be read
+// comes with the dir it was in. The rest is sent whole.
+//
+#[derive(Debug, Hash)]
+pub struct StateUpdate {
+    pub dir_change_list: Vec<DirChange>,
+    // What a filter leaves shown, if that has changed.
+    pub filter_change: Option<FilterChange>,
+    pub size_mode: SizeMode,
+    pub marked_summary: MarkedSummary,
+    pub scan_progress: ScanProgress,
+}
+
+#[derive(Debug, Hash)]
+pub enum DirChange {
+    // Listed, or listed again, so all of it is new. As held in the DirHash, so sub dirs
+    // are DirRefs.
+    Listed(Dir),
+    Updated(DirUpdate),
+    Removed(PathBuf),
+}
+
+//
+// What may change in a dir that hasn't been listed again: its sizes, and what the user
+// has done to it. 'entry_list' holds the entries that have changed (been marked), as
+// they are now.
+//
+#[derive(Debug, Hash)]
+pub struct DirUpdate {
+    pub path: PathBuf,
+    pub size_in_bytes: u64,
+    pub disk_size_in_bytes: u64,
+    pub is_open: bool,
+    pub is_marked: bool,
+    pub entry_list: Vec<FSObj>,
+}
+
+#[derive(Debug, Hash)]
+pub enum FilterChange {
+    Off,
+    // A new filter, and every path it leaves shown.
+    On(Vec<PathBuf>),
+    // The paths the filter has come to leave shown since the last update, and those it
+    // no longer does.
+    Changed {
+        shown_path_list: Vec<PathBuf>,
+        hidden_path_list: Vec<PathBuf>,
+    },
+}
+
 #[derive(Debug, Hash)]
 pub struct GetStateResponse {
     pub dirp_state: Dir,
+    pub size_mode: SizeMode,
+    pub marked_summary: MarkedSummary,
+    pub scan_progress: ScanProgress,
+    // Every path that couldn't be read, sorted by path.
+    pub scan_error_list: Vec<ScanError>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Hash)]
+pub struct MarkedSummary {
+    pub size_in_bytes: u64,
+    // What removing the marked files would actually free, allowing for hard links.
+    pub reclaimable_size_in_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Hash)]
+pub struct ScanProgress {
+    pub dirs_scanned: u64,
+    pub files_scanned: u64,
+    // The root dir's size so far.
+    pub size_in_bytes: u64,
+    // Scans handed to the thread pool that haven't reported back.
+    pub pending_scan_count: usize,
+    // Set once the first full scan is done. Every size is final at that point.
+    pub is_complete: bool,
+    // How long the first full scan has taken (so far, if it's not complete).
+    pub elapsed: Duration,
 }
 
 pub struct IntermediateState {
     pub ui_row: Vec<String>,
     pub is_marked: bool,
-    pub path: String,
+    pub is_excluded: bool,
+    pub has_error: bool,
+}
+
+pub enum Command {
+    // Browse the tree interactively.
+    Ui,
+    // Print the tree, down to 'depth' levels below the root, and exit.
+    Report {
+        depth: u32,
+    },
+    // Write the tree to 'export_path', and exit.
+    Export {
+        export_path: PathBuf,
+        export_format: ExportFormat,
+    },
+    // Check the entries of the removal plan at 'plan_path' against the file system, and
+    // remove those that still match.
+    Apply {
+        plan_path: PathBuf,
+    },
+    // Put back what the last session moved to the Trash.
+    Undo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    // dirp's own JSON format. See export.rs.
+    Json,
+    // ncdu's JSON dump format. See ncdu.rs.
+    Ncdu,
 }
 
 pub struct Args {
+    pub command: Command,
     pub path: PathBuf,
+    pub size_mode: SizeMode,
+    pub remove_mode: RemoveMode,
+    // When set, 'x' writes a removal plan (to 'plan_path', or stdout) instead of removing
+    // anything.
+    pub is_dry_run: bool,
+    pub plan_path: Option<PathBuf>,
+    // Don't ask before applying a removal plan.
+    pub is_confirmed: bool,
+    pub scan_source: ScanSource,
+    pub scan_options: ScanOptions,
+    // Where to log to, if anywhere, and how much.
+    pub log_path: Option<PathBuf>,
+    pub log_level: LevelFilter,
+}
+
+//
+// Where the tree comes from.
+//
+pub enum ScanSource {
+    // Scan the file system under the root path, and keep watching it for changes.
+    Disk,
+    // A full tree, loaded from a saved scan. The file system isn't touched.
+    Snapshot(Dir),
+}
+
+//
+// Settings that shape what scan_dir_path() looks at.
+//
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    // When set, dirs on any other device are listed as mount points, and not scanned.
+    pub root_dev: Option<u64>,
+    pub exclude_rules: ExcludeRules,
+    // Excluded paths are left out, unless this is set. Then they are scanned and listed
+    // (greyed out), but their sizes are kept out of their parent dirs' sizes.
+    pub show_excluded: bool,
+    // Unchanged dirs are taken from here, rather than read again.
+    pub scan_cache: Option<Arc<ScanCache>>,
 }
 
 pub struct DirpState {
@@ -121,13 +600,21 @@ pub struct DirpState {
 }
 
 impl DirpState {
-    pub fn new(path: String) -> DirpState {
+    pub fn new(
+        path: PathBuf,
+        size_mode: SizeMode,
+        scan_source: ScanSource,
+        scan_options: ScanOptions,
+    ) -> DirpState {
         let (dirp_state_sender, dirp_state_receiver) = channel();
         let (user_sender, user_receiver) = channel();
 
         // Spawn a long running task to manage dirp state.
         let thread_handle = dirp_state_thread_spawn(
             path,
+            size_mode,
+            scan_source,
+            Arc::new(scan_options),
             user_sender.clone(),
             dirp_state_sender.clone(),
             dirp_state_receiver,
@@ -159,6 +646,19 @@ impl DirpState {
         }
     }
 
+    //
+    // Waits for the scan to complete, and returns the whole tree. Any state responses
+    // that come in meanwhile are dropped.
+    //
+    pub fn full_state(&self) -> Result<GetStateResponse, DirpError> {
+        self.send(DirpStateMessage::GetFullState);
+        loop {
+            if let UserMessage::GetFullStateResponse(state_response) = self.user_receiver.recv()? {
+                return Ok(state_response);
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn quit(self) {
         if let Err(error) = self.dirp_state_sender.send(DirpStateMessage::Quit) {
@@ -180,9 +680,31 @@ impl DirpState {
 pub enum DirpError {
     StdIoError(std::io::Error),
     RecvError(std::sync::mpsc::RecvError),
-    SendErrorDirpStateMessage(std::sync::mpsc::SendError<DirpStateMessage>),
-    SendErrorUserMessage(std::sync::mpsc::SendError<UserMessage>),
+    // The boxed ones are much larger than the others, and this is returned everywhere.
+    SendErrorDirpStateMessage(Box<std::sync::mpsc::SendError<DirpStateMessage>>),
+    SendErrorUserMessage(Box<std::sync::mpsc::SendError<UserMessage>>),
+    NotifyError(Box<notify::Error>),
     TrashError(trash::Error),
+    JsonError(serde_json::Error),
+    // A saved scan that can't be read.
+    BadScanFile(String),
+    BadPlanFile(String),
+}
+
+impl fmt::Display for DirpError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirpError::StdIoError(error) => write!(formatter, "{}", error),
+            DirpError::RecvError(error) => write!(formatter, "{}", error),
+            DirpError::SendErrorDirpStateMessage(error) => write!(formatter, "{}", error),
+            DirpError::SendErrorUserMessage(error) => write!(formatter, "{}", error),
+            DirpError::TrashError(error) => write!(formatter, "Trash: {}", error),
+            DirpError::NotifyError(error) => write!(formatter, "Watching for changes: {}", error),
+            DirpError::JsonError(error) => write!(formatter, "JSON: {}", error),
+            DirpError::BadScanFile(message) => write!(formatter, "Bad scan file: {}", message),
+            DirpError::BadPlanFile(message) => write!(formatter, "Bad plan file: {}", message),
+        }
+    }
 }
 
 impl From<std::io::Error> for DirpError {
@@ -199,13 +721,13 @@ impl From<std::sync::mpsc::RecvError> for DirpError {
 
 impl From<std::sync::mpsc::SendError<DirpStateMessage>> for DirpError {
     fn from(error: std::sync::mpsc::SendError<DirpStateMessage>) -> Self {
-        DirpError::SendErrorDirpStateMessage(error)
+        DirpError::SendErrorDirpStateMessage(Box::new(error))
     }
 }
 
 impl From<std::sync::mpsc::SendError<UserMessage>> for DirpError {
     fn from(error: std::sync::mpsc::SendError<UserMessage>) -> Self {
-        DirpError::SendErrorUserMessage(error)
+        DirpError::SendErrorUserMessage(Box::new(error))
     }
 }
 
@@ -214,3 +736,15 @@ impl From<trash::Error> for DirpError {
         DirpError::TrashError(error)
     }
 }
+
+impl From<notify::Error> for DirpError {
+    fn from(error: notify::Error) -> Self {
+        DirpError::NotifyError(Box::new(error))
+    }
+}
+
+impl From<serde_json::Error> for DirpError {
+    fn from(error: serde_json::Error) -> Self {
+        DirpError::JsonError(error)
+    }
+}
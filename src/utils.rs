@@ -1,8 +1,12 @@
 use crate::types::*;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use log::{error, warn};
 use std::fs::DirEntry;
-use std::str::FromStr;
-use std::sync::mpsc::Sender;
-use std::{fs, path::PathBuf};
+use std::sync::{mpsc::Sender, Arc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use threadpool::ThreadPool;
 
 #[cfg(target_os = "linux")]
@@ -13,96 +17,222 @@ use std::os::macos::fs::MetadataExt;
 use std::os::windows::fs::MetadataExt;
 
 pub fn scan_dir_path_in_threadpool(
-    dir_path: String,
+    dir_path: PathBuf,
     is_open: bool,
+    scan_options: Arc<ScanOptions>,
     dirp_state_sender: Sender<DirpStateMessage>,
     threadpool: &ThreadPool,
 ) {
     threadpool.execute(move || {
-        if let Err(_error) = scan_dir_path(dir_path.clone(), is_open, dirp_state_sender) {
-            //            panic!("scan_dir_path path: '{}' error: {:#?}", dir_path, error);
-            // ToDo: Log this error.
+        if let Err(error) =
+            scan_dir_path(dir_path.clone(), is_open, &scan_options, dirp_state_sender)
+        {
+            // Read errors go to the state thread with the dir. This is failing to get
+            // there at all.
+            error!(
+                "scan_dir_path path: '{}' error: {:?}",
+                dir_path.display(),
+                error
+            );
         }
     });
 }
 
 pub fn scan_dir_path(
-    dir_path: String,
+    dir_path: PathBuf,
     is_open: bool,
+    scan_options: &ScanOptions,
     dirp_state_sender: Sender<DirpStateMessage>,
 ) -> Result<(), DirpError> {
-    // Create a list containing a FSObj for each directory item in the
-    // specified dir
-    let mut fs_obj_list = FSObjList::new();
+    // The dir's own meta data is read first, so anything that changes the dir after
+    // this is seen the next time round.
+    let dir_meta_data = fs::symlink_metadata(&dir_path).ok();
 
-    match fs::read_dir(dir_path.clone()) {
-        Ok(read_dir) => {
-            for dir_entry in read_dir {
-                let result =
-                    |dir_entry: Result<DirEntry, std::io::Error>| -> Result<(), DirpError> {
-                        let dir_entry = dir_entry?;
-                        let obj_path = dir_entry.path();
-                        let obj_path_string = obj_path.to_string_lossy().to_string();
-                        let meta_data = obj_path.symlink_metadata()?;
-
-                        if std::env::consts::OS == "macos" {
-                            if dir_entry.file_name() == ".DS_Store" {
-                                return Ok(());
-                            } else if obj_path_string == "/Volumes" {
-                                return Ok(());
-                            } else if obj_path_string == "/System/Volumes" {
-                                return Ok(());
-                            }
-                        }
-
-                        if meta_data.is_symlink() {
-                            fs_obj_list.push(FSObj::SymLink(SymLink {
-                                path: obj_path_string,
-                                size_in_bytes: meta_data.st_size(),
-                                percent: 0,
-                                is_marked: false,
-                            }));
-                        } else if meta_data.is_dir() {
-                            fs_obj_list.push(FSObj::DirRef(DirRef {
-                                path: obj_path_string,
-                                is_open,
-                                size_in_bytes: 0,
-                                percent: 0,
-                                is_marked: false,
-                            }));
-                        } else if meta_data.is_file() {
-                            fs_obj_list.push(FSObj::File(File {
-                                path: obj_path_string,
-                                size_in_bytes: meta_data.st_size(),
-                                percent: 0,
-                                is_marked: false,
-                            }));
-                        }
-                        Ok(())
-                    }(dir_entry);
-                if let Err(_error) = result {
-                    // ToDo: Add logging of this error.
+    let cached_fs_obj_list = match (&scan_options.scan_cache, &dir_meta_data) {
+        (Some(scan_cache), Some(meta_data)) => {
+            scan_cache.take(&dir_path, meta_data, is_open, scan_options)
+        }
+        _ => None,
+    };
+    let mut error_list = Vec::new();
+    let fs_obj_list = match cached_fs_obj_list {
+        Some(fs_obj_list) => fs_obj_list,
+        None => match read_dir_obj_list(&dir_path, is_open, scan_options, &mut error_list) {
+            Ok(fs_obj_list) => {
+                // A dir that was only partly read is read again next time.
+                if let (Some(scan_cache), Some(meta_data), true) = (
+                    &scan_options.scan_cache,
+                    &dir_meta_data,
+                    error_list.is_empty(),
+                ) {
+                    scan_cache.insert(&dir_path, meta_data, &fs_obj_list, scan_options);
                 }
+                fs_obj_list
             }
+            Err(error) => {
+                error_list.push(ScanError {
+                    path: dir_path.clone(),
+                    message: error.to_string(),
+                });
+                FSObjList::new()
+            }
+        },
+    };
+
+    // The dir itself is judged by the rules that apply in its parent, just as it was
+    // when the parent was listed.
+    let is_excluded = match dir_path.parent() {
+        Some(parent_path) if !parent_path.as_os_str().is_empty() => {
+            scan_options.exclude_rules.is_excluded(
+                &dir_path,
+                true,
+                &scan_options.exclude_rules.ignore_file_chain(parent_path),
+            )
         }
-        Err(_error) => {
-            // ToDo: Log this error.
-        }
+        _ => false,
+    };
+
+    for error in &error_list {
+        warn!("{}: {}", error.path.display(), error.message);
     }
 
+    let mtime = dir_meta_data
+        .map(|meta_data| meta_data.st_mtime())
+        .unwrap_or(0);
+
     // Sent it to the state managing thread.
     dirp_state_sender.send(DirpStateMessage::DirScanMessage(Dir {
         path: dir_path,
         is_open,
         size_in_bytes: 0,
+        disk_size_in_bytes: 0,
         percent: 0,
         is_marked: false,
+        is_excluded,
+        mtime,
         dir_obj_list: fs_obj_list,
+        error_list,
     }))?;
 
     Ok(())
 }
 
+//
+// Lists the dir at 'dir_path'. Entries that can't be read are left out, and added to
+// 'error_list'. Only failing to read the dir itself is an error.
+//
+fn read_dir_obj_list(
+    dir_path: &Path,
+    is_open: bool,
+    scan_options: &ScanOptions,
+    error_list: &mut Vec<ScanError>,
+) -> Result<FSObjList, std::io::Error> {
+    // Create a list containing a FSObj for each directory item in the
+    // specified dir
+    let mut fs_obj_list = FSObjList::new();
+    let ignore_file_chain = scan_options.exclude_rules.ignore_file_chain(dir_path);
+
+    let read_dir = fs::read_dir(dir_path)?;
+    for dir_entry in read_dir {
+        // Where an entry can't even be named, the error is put down to the dir.
+        let error_path = match &dir_entry {
+            Ok(dir_entry) => dir_entry.path(),
+            Err(_) => dir_path.to_path_buf(),
+        };
+        let result = |dir_entry: Result<DirEntry, std::io::Error>| -> Result<(), std::io::Error> {
+            let dir_entry = dir_entry?;
+            let obj_path = dir_entry.path();
+            let meta_data = obj_path.symlink_metadata()?;
+
+            if std::env::consts::OS == "macos" && dir_entry.file_name() == ".DS_Store" {
+                return Ok(());
+            }
+
+            let is_excluded = scan_options.exclude_rules.is_excluded(
+                &obj_path,
+                meta_data.is_dir(),
+                &ignore_file_chain,
+            );
+            if is_excluded && !scan_options.show_excluded {
+                return Ok(());
+            }
+
+            if let Some(fs_obj) =
+                fs_obj_for_meta_data(obj_path, &meta_data, is_open, is_excluded, scan_options)
+            {
+                fs_obj_list.push(fs_obj);
+            }
+            Ok(())
+        }(dir_entry);
+        if let Err(error) = result {
+            error_list.push(ScanError {
+                path: error_path,
+                message: error.to_string(),
+            });
+        }
+    }
+
+    Ok(fs_obj_list)
+}
+
+//
+// The entry at 'obj_path', from its meta data. None for what isn't listed (sockets,
+// devices and the like).
+//
+pub fn fs_obj_for_meta_data(
+    obj_path: PathBuf,
+    meta_data: &fs::Metadata,
+    is_open: bool,
+    is_excluded: bool,
+    scan_options: &ScanOptions,
+) -> Option<FSObj> {
+    let fs_obj = if meta_data.is_symlink() {
+        FSObj::SymLink(SymLink {
+            path: obj_path,
+            size_in_bytes: meta_data.st_size(),
+            disk_size_in_bytes: meta_data.st_blocks() * 512,
+            percent: 0,
+            is_marked: false,
+            is_excluded,
+            mtime: meta_data.st_mtime(),
+        })
+    } else if meta_data.is_dir()
+        && scan_options
+            .root_dev
+            .is_some_and(|root_dev| meta_data.st_dev() != root_dev)
+    {
+        FSObj::MountPoint(MountPoint { path: obj_path })
+    } else if meta_data.is_dir() {
+        FSObj::DirRef(DirRef {
+            path: obj_path,
+            is_open,
+            size_in_bytes: 0,
+            disk_size_in_bytes: 0,
+            percent: 0,
+            is_marked: false,
+            is_excluded,
+            mtime: meta_data.st_mtime(),
+        })
+    } else if meta_data.is_file() {
+        FSObj::File(File {
+            path: obj_path,
+            size_in_bytes: meta_data.st_size(),
+            disk_size_in_bytes: meta_data.st_blocks() * 512,
+            percent: 0,
+            is_marked: false,
+            is_excluded,
+            mtime: meta_data.st_mtime(),
+            dev: meta_data.st_dev(),
+            ino: meta_data.st_ino(),
+            nlink: meta_data.st_nlink(),
+        })
+    } else {
+        return None;
+    };
+
+    Some(fs_obj)
+}
+
 pub fn indent_prefix_for_level(level: u32) -> String {
     let mut result = "".to_string();
     for _ in 1..level {
@@ -123,59 +253,109 @@ pub fn human_readable_bytes(bytes: u64) -> String {
     }
 }
 
-pub fn parent_file_path(file_path: &String) -> Option<String> {
-    if let Ok(path) = PathBuf::from_str(&file_path) {
-        if let Some(parent) = path.parent() {
-            return Some(parent.to_string_lossy().to_string());
-        }
+pub fn parent_file_path(file_path: &Path) -> Option<PathBuf> {
+    file_path.parent().map(Path::to_path_buf)
+}
+
+//
+// The name at the end of 'file_path', for display. Anything that isn't UTF-8 is shown
+// as the replacement character.
+//
+pub fn file_name(file_path: &Path) -> Option<String> {
+    if file_path == Path::new("/") {
+        return Some("/".to_string());
     }
-    None
+    file_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
 }
 
-pub fn file_name(file_path: &String) -> Option<String> {
-    if file_path == "/" {
-        return Some(file_path.clone());
-    } else if let Ok(path) = PathBuf::from_str(file_path) {
-        if let Some(file_name) = path.file_name() {
-            return Some(file_name.to_string_lossy().to_string());
-        }
+pub fn fs_obj_path(fs_obj: &FSObj) -> &Path {
+    match fs_obj {
+        FSObj::Dir(obj) => &obj.path,
+        FSObj::DirRef(obj) => &obj.path,
+        FSObj::File(obj) => &obj.path,
+        FSObj::SymLink(obj) => &obj.path,
+        FSObj::MountPoint(obj) => &obj.path,
     }
-    None
+}
+
+//
+// Whether the name at the end of 'path' fuzzy matches 'query', the way dialoguer's
+// FuzzySelect does (e.g. 'nmod' matches 'node_modules').
+//
+pub fn is_name_match(matcher: &SkimMatcherV2, path: &Path, query: &str) -> bool {
+    file_name(path).is_some_and(|name| matcher.fuzzy_match(&name, query).is_some())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
     use std::sync::mpsc::channel;
-    use std::thread::sleep;
-    use std::time::Duration;
 
     #[test]
     fn test_scan_dir_path_task() -> Result<(), DirpError> {
         let threadpool = ThreadPool::new(30);
         let (sender, receiver) = channel();
 
-        scan_dir_path_in_threadpool("./test/a".to_string(), true, sender.clone(), &threadpool);
-
-        sleep(Duration::from_secs(1));
+        scan_dir_path_in_threadpool(
+            PathBuf::from("./test/a"),
+            true,
+            Arc::new(ScanOptions::default()),
+            sender.clone(),
+            &threadpool,
+        );
 
         let dirp_state_message = receiver.recv()?;
         if let DirpStateMessage::DirScanMessage(dir) = dirp_state_message {
-            let expected_hash = 15929552558369993273 as u64;
-            let mut hasher = DefaultHasher::new();
-            dir.hash(&mut hasher);
-            let hash = hasher.finish();
-
             println!("Dir: {:#?}", dir);
-            println!("Hash: {}", hash);
 
-            assert_eq!(hash, expected_hash, "Error: Unexpected result.");
+            let mut path_list = Vec::new();
+            for fs_obj in &dir.dir_obj_list {
+                if let FSObj::File(file) = fs_obj {
+                    // Disk usage depends on the file system, but is always whole blocks.
+                    assert_eq!(file.size_in_bytes, 1010, "Error: Unexpected size.");
+                    assert_eq!(file.disk_size_in_bytes % 512, 0, "Error: Unexpected size.");
+                    path_list.push(file.path.clone());
+                } else {
+                    assert!(false, "Error: Unexpected fs obj type");
+                }
+            }
+            path_list.sort();
+
+            assert_eq!(
+                path_list,
+                vec![
+                    PathBuf::from("./test/a/1.txt"),
+                    PathBuf::from("./test/a/2.txt"),
+                    PathBuf::from("./test/a/3.txt")
+                ],
+                "Error: Unexpected result."
+            );
         } else {
             assert!(false, "Error: Unexpected message type");
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_scan_dir_path_error() -> Result<(), DirpError> {
+        // A dir that can't be read still gets sent, empty, with what went wrong.
+        let (sender, receiver) = channel();
+        let dir_path = PathBuf::from("./test/a/1.txt");
+        scan_dir_path(dir_path.clone(), true, &ScanOptions::default(), sender)?;
+
+        let DirpStateMessage::DirScanMessage(dir) = receiver.recv()? else {
+            panic!("Error: Unexpected message type");
+        };
+        assert!(dir.dir_obj_list.is_empty(), "Error: Unexpected result.");
+        assert_eq!(dir.error_list.len(), 1, "Error: Unexpected result.");
+        assert_eq!(
+            dir.error_list[0].path, dir_path,
+            "Error: Unexpected result."
+        );
+
+        Ok(())
+    }
 }